@@ -1,10 +1,14 @@
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Type {
     I8,
     I16,
     I32,
     I64,
     Unit,
+    F32,
+    F64,
+    Struct(u32),
+    Array(Box<Type>, u32),
 }
 
 impl Type {
@@ -16,11 +20,37 @@ impl Type {
             2 => I32,
             3 => I64,
             4 => Unit,
+            5 => F32,
+            6 => F64,
+            _ => return None,
+        })
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, Type::F32 | Type::F64)
+    }
+
+    pub fn is_aggregate(&self) -> bool {
+        matches!(self, Type::Struct(_) | Type::Array(..))
+    }
+
+    pub fn is_int(&self) -> bool {
+        self.int_width().is_some()
+    }
+
+    pub fn int_width(&self) -> Option<u32> {
+        Some(match self {
+            Type::I8 => 8,
+            Type::I16 => 16,
+            Type::I32 => 32,
+            Type::I64 => 64,
             _ => return None,
         })
     }
 }
 
+/// Arithmetic and comparison operators also apply to floats, where signed comparisons are ordered
+/// and unsigned comparisons are unordered.
 #[derive(Debug)]
 pub enum BinaryOp {
     Add,
@@ -29,6 +59,23 @@ pub enum BinaryOp {
     Div,
     Lsh,
     Rsh,
+    Eq,
+    Ne,
+    SLt,
+    SLe,
+    SGt,
+    SGe,
+    ULt,
+    ULe,
+    UGt,
+    UGe,
+    And,
+    Or,
+    Xor,
+    SRem,
+    URem,
+    UDiv,
+    URsh,
 }
 
 impl BinaryOp {
@@ -41,6 +88,67 @@ impl BinaryOp {
             3 => Div,
             4 => Lsh,
             5 => Rsh,
+            6 => Eq,
+            7 => Ne,
+            8 => SLt,
+            9 => SLe,
+            10 => SGt,
+            11 => SGe,
+            12 => ULt,
+            13 => ULe,
+            14 => UGt,
+            15 => UGe,
+            16 => And,
+            17 => Or,
+            18 => Xor,
+            19 => SRem,
+            20 => URem,
+            21 => UDiv,
+            22 => URsh,
+            _ => return None,
+        })
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        use BinaryOp::*;
+        matches!(
+            self,
+            Eq | Ne | SLt | SLe | SGt | SGe | ULt | ULe | UGt | UGe
+        )
+    }
+
+    pub fn applies_to_floats(&self) -> bool {
+        use BinaryOp::*;
+        self.is_comparison() || matches!(self, Add | Sub | Mult | Div | SRem)
+    }
+}
+
+#[derive(Debug)]
+pub enum CastOp {
+    SExt,
+    ZExt,
+    Trunc,
+    SIToFP,
+    UIToFP,
+    FPToSI,
+    FPToUI,
+    FPExt,
+    FPTrunc,
+}
+
+impl CastOp {
+    pub fn from_id(id: u32) -> Option<CastOp> {
+        use CastOp::*;
+        Some(match id {
+            0 => SExt,
+            1 => ZExt,
+            2 => Trunc,
+            3 => SIToFP,
+            4 => UIToFP,
+            5 => FPToSI,
+            6 => FPToUI,
+            7 => FPExt,
+            8 => FPTrunc,
             _ => return None,
         })
     }
@@ -51,20 +159,48 @@ pub struct BinaryExpr {
     pub op: BinaryOp,
     pub a: Box<Expr>,
     pub b: Box<Expr>,
+    /// The type comparison results are zero-extended to.
+    pub ty: Option<Type>,
+}
+
+#[derive(Debug)]
+pub enum Extension {
+    Sign,
+    Zero,
+}
+
+impl Extension {
+    pub fn from_id(id: u32) -> Option<Extension> {
+        use Extension::*;
+        Some(match id {
+            0 => Sign,
+            1 => Zero,
+            _ => return None,
+        })
+    }
 }
 
 #[derive(Debug)]
 pub enum UnaryOp {
-    Deref,
+    /// Loads a `ty` from memory, optionally extending it to 64 bits.
+    Deref {
+        ty: Type,
+        ext: Option<Extension>,
+    },
     Not,
 }
 
 impl UnaryOp {
-    pub fn from_id(id: u32) -> Option<UnaryOp> {
+    /// Builds the operator with the given id, where `ty` and `ext` are the load type and extension
+    /// of a deref. A deref without a type loads an i32, and other operators can't have either.
+    pub fn from_id(id: u32, ty: Option<Type>, ext: Option<Extension>) -> Option<UnaryOp> {
         use UnaryOp::*;
-        Some(match id {
-            0 => Deref,
-            1 => Not,
+        Some(match (id, ty, ext) {
+            (0, ty, ext) => Deref {
+                ty: ty.unwrap_or(Type::I32),
+                ext,
+            },
+            (1, None, None) => Not,
             _ => return None,
         })
     }
@@ -101,12 +237,18 @@ pub struct LocalExpr {
 #[derive(Debug)]
 pub struct ConstantExpr {
     pub ty: Type,
-    pub val: u32,
+    pub val: i64,
+}
+
+#[derive(Debug)]
+pub struct FloatConstantExpr {
+    pub ty: Type,
+    pub val: f64,
 }
 
 #[derive(Debug)]
 pub struct ReturnExpr {
-    pub val: Box<Expr>,
+    pub val: Option<Box<Expr>>,
 }
 
 #[derive(Debug)]
@@ -115,7 +257,107 @@ pub struct StringLitExpr {
 }
 
 #[derive(Debug)]
-pub enum Expr {
+pub struct IfExpr {
+    pub cond: Box<Expr>,
+    pub then_branch: Box<Expr>,
+    pub else_branch: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct WhileExpr {
+    pub cond: Box<Expr>,
+    pub body: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct StoreExpr {
+    pub ty: Type,
+    pub addr: Box<Expr>,
+    pub val: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct AddrOfExpr {
+    pub local: u32,
+}
+
+#[derive(Debug)]
+pub struct CastExpr {
+    pub op: CastOp,
+    pub ty: Type,
+    pub val: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct GlobalGetExpr {
+    pub global: u32,
+}
+
+#[derive(Debug)]
+pub struct GlobalSetExpr {
+    pub global: u32,
+    pub val: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub struct GlobalAddrExpr {
+    pub global: u32,
+}
+
+#[derive(Debug)]
+pub enum Access {
+    Load,
+    Store(Box<Expr>),
+    Address,
+}
+
+#[derive(Debug)]
+pub struct FieldExpr {
+    pub struct_id: u32,
+    pub field: u32,
+    pub addr: Box<Expr>,
+    pub access: Access,
+}
+
+#[derive(Debug)]
+pub struct IndexExpr {
+    pub elem_ty: Type,
+    pub addr: Box<Expr>,
+    pub index: Box<Expr>,
+    pub access: Access,
+}
+
+#[derive(Debug)]
+pub struct FuncAddrExpr {
+    pub func_name: String,
+}
+
+#[derive(Debug)]
+pub struct IndirectCallExpr {
+    /// The declaration whose signature the callee has.
+    pub sig_name: String,
+    pub callee: Box<Expr>,
+    pub params: Vec<Expr>,
+}
+
+#[derive(Debug)]
+pub struct SwitchCase {
+    pub val: ConstantExpr,
+    pub body: Expr,
+    /// The location of the case value.
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug)]
+pub struct SwitchExpr {
+    pub scrutinee: Box<Expr>,
+    pub cases: Vec<SwitchCase>,
+    pub default: Box<Expr>,
+}
+
+#[derive(Debug)]
+pub enum ExprKind {
     Binary(BinaryExpr),
     Unary(UnaryExpr),
     Invoke(InvokeExpr),
@@ -123,8 +365,80 @@ pub enum Expr {
     Assignment(AssignmentExpr),
     Local(LocalExpr),
     Constant(ConstantExpr),
+    FloatConstant(FloatConstantExpr),
     Return(ReturnExpr),
     StringLit(StringLitExpr),
+    If(IfExpr),
+    While(WhileExpr),
+    Break,
+    Continue,
+    Store(StoreExpr),
+    AddrOf(AddrOfExpr),
+    Cast(CastExpr),
+    GlobalGet(GlobalGetExpr),
+    GlobalSet(GlobalSetExpr),
+    GlobalAddr(GlobalAddrExpr),
+    Field(FieldExpr),
+    Index(IndexExpr),
+    FuncAddr(FuncAddrExpr),
+    IndirectCall(IndirectCallExpr),
+    Switch(SwitchExpr),
+}
+
+#[derive(Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// The location of the token the expression starts at.
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Expr {
+    /// Whether the expression always jumps elsewhere before producing a value, which is the case
+    /// once any part of it that is always evaluated does. Code after it in a block can never run.
+    pub fn diverges(&self) -> bool {
+        let access_diverges = |access: &Access| match access {
+            Access::Store(val) => val.diverges(),
+            Access::Load | Access::Address => false,
+        };
+        match &self.kind {
+            ExprKind::Return(_) | ExprKind::Break | ExprKind::Continue => true,
+            ExprKind::Binary(expr) => expr.a.diverges() || expr.b.diverges(),
+            ExprKind::Unary(expr) => expr.a.diverges(),
+            ExprKind::Invoke(expr) => expr.params.iter().any(Expr::diverges),
+            ExprKind::Block(expr) => expr.exprs.iter().any(Expr::diverges),
+            ExprKind::Assignment(expr) => expr.val.diverges(),
+            ExprKind::If(expr) => {
+                expr.cond.diverges() || (expr.then_branch.diverges() && expr.else_branch.diverges())
+            }
+            // The body may break out of the loop, so only the first evaluation of the condition
+            // is certain to happen
+            ExprKind::While(expr) => expr.cond.diverges(),
+            ExprKind::Store(expr) => expr.addr.diverges() || expr.val.diverges(),
+            ExprKind::Cast(expr) => expr.val.diverges(),
+            ExprKind::GlobalSet(expr) => expr.val.diverges(),
+            ExprKind::Field(expr) => expr.addr.diverges() || access_diverges(&expr.access),
+            ExprKind::Index(expr) => {
+                expr.addr.diverges() || expr.index.diverges() || access_diverges(&expr.access)
+            }
+            ExprKind::IndirectCall(expr) => {
+                expr.callee.diverges() || expr.params.iter().any(Expr::diverges)
+            }
+            ExprKind::Switch(expr) => {
+                expr.scrutinee.diverges()
+                    || (expr.cases.iter().all(|case| case.body.diverges())
+                        && expr.default.diverges())
+            }
+            ExprKind::Local(_)
+            | ExprKind::Constant(_)
+            | ExprKind::FloatConstant(_)
+            | ExprKind::StringLit(_)
+            | ExprKind::AddrOf(_)
+            | ExprKind::GlobalGet(_)
+            | ExprKind::GlobalAddr(_)
+            | ExprKind::FuncAddr(_) => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -133,12 +447,24 @@ pub enum Linkage {
     Internal,
 }
 
+impl Linkage {
+    pub fn from_id(id: u32) -> Option<Linkage> {
+        use Linkage::*;
+        Some(match id {
+            0 => External,
+            1 => Internal,
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct FunctionDeclItem {
     pub name: String,
     pub return_ty: Type,
     pub params: Vec<Type>,
     pub linkage: Linkage,
+    pub variadic: bool,
 }
 
 #[derive(Debug)]
@@ -146,10 +472,30 @@ pub struct FunctionDefItem {
     pub name: String,
     pub locals: Vec<Type>,
     pub code: Expr,
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug)]
+pub struct GlobalItem {
+    pub name: String,
+    pub ty: Type,
+    pub linkage: Linkage,
+    pub mutable: bool,
+    /// Either a `Constant` or `FloatConstant` expression.
+    pub init: Option<Expr>,
+}
+
+#[derive(Debug)]
+pub struct StructItem {
+    pub name: String,
+    pub fields: Vec<Type>,
 }
 
 #[derive(Debug)]
 pub enum Item {
     FunctionDecl(FunctionDeclItem),
     FunctionDef(FunctionDefItem),
+    Global(GlobalItem),
+    Struct(StructItem),
 }
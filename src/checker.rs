@@ -0,0 +1,527 @@
+use crate::ast::*;
+use crate::error::Error;
+use std::collections::HashSet;
+
+/// Checks a parsed program for the errors that LLVM would otherwise only catch while verifying the
+/// generated IR, returning all of them.
+pub fn check(items: &[Item]) -> Vec<Error> {
+    let mut checker = Checker {
+        items,
+        errors: Vec::new(),
+        return_ty: Type::Unit,
+        num_params: 0,
+        vars: Vec::new(),
+    };
+
+    for item in items {
+        match item {
+            Item::FunctionDef(def) => checker.check_function(def),
+            Item::Global(global) => checker.check_global(global),
+            Item::FunctionDecl(_) | Item::Struct(_) => {}
+        }
+    }
+
+    checker.errors
+}
+
+/// The bits of an integer constant that codegen keeps for its type.
+fn truncate(val: i64, ty: &Type) -> u64 {
+    (val as u64) & (u64::MAX >> (64 - ty.int_width().unwrap()))
+}
+
+fn is_scalar(ty: &Type) -> bool {
+    !ty.is_aggregate() && *ty != Type::Unit
+}
+
+/// Whether a value of type `from` can be used where a `to` is expected. Integers and floats are
+/// converted to the width the destination expects, but never into each other.
+fn coerces_to(from: &Type, to: &Type) -> bool {
+    (from.is_int() && to.is_int()) || (from.is_float() && to.is_float()) || from == to
+}
+
+fn valid_cast(op: &CastOp, from: &Type, to: &Type) -> bool {
+    match op {
+        CastOp::SExt | CastOp::ZExt => {
+            matches!((from.int_width(), to.int_width()), (Some(a), Some(b)) if a < b)
+        }
+        CastOp::Trunc => matches!((from.int_width(), to.int_width()), (Some(a), Some(b)) if a > b),
+        CastOp::SIToFP | CastOp::UIToFP => from.is_int() && to.is_float(),
+        CastOp::FPToSI | CastOp::FPToUI => from.is_float() && to.is_int(),
+        CastOp::FPExt => *from == Type::F32 && *to == Type::F64,
+        CastOp::FPTrunc => *from == Type::F64 && *to == Type::F32,
+    }
+}
+
+struct Checker<'a> {
+    items: &'a [Item],
+    errors: Vec<Error>,
+
+    return_ty: Type,
+    num_params: usize,
+    /// The types of the current function's parameters followed by its locals.
+    vars: Vec<Type>,
+}
+
+impl<'a> Checker<'a> {
+    fn error(&mut self, expr: &Expr, msg: String) {
+        self.errors.push(Error {
+            line: expr.line,
+            col: expr.col,
+            msg: Some(msg),
+        });
+    }
+
+    fn find_decl(&self, name: &str) -> Option<&'a FunctionDeclItem> {
+        self.items.iter().find_map(|item| match item {
+            Item::FunctionDecl(decl) if decl.name == name => Some(decl),
+            _ => None,
+        })
+    }
+
+    fn find_global(&self, index: u32) -> &'a GlobalItem {
+        self.items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Global(global) => Some(global),
+                _ => None,
+            })
+            .nth(index as usize)
+            .unwrap()
+    }
+
+    fn find_struct(&self, index: u32) -> &'a StructItem {
+        self.items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Struct(def) => Some(def),
+                _ => None,
+            })
+            .nth(index as usize)
+            .unwrap()
+    }
+
+    fn check_global(&mut self, global: &GlobalItem) {
+        let init = match &global.init {
+            Some(init) => init,
+            None => return,
+        };
+        // Initializers are emitted as constants, so there is nothing to convert them
+        if let Some(ty) = self.check_expr(init) {
+            if ty != global.ty {
+                self.error(
+                    init,
+                    format!(
+                        "expected {:?} to initialize `{}`, found {:?}",
+                        global.ty, global.name, ty
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_function(&mut self, def: &FunctionDefItem) {
+        let decl = match self.find_decl(&def.name) {
+            Some(decl) => decl,
+            None => {
+                self.errors.push(Error {
+                    line: def.line,
+                    col: def.col,
+                    msg: Some(format!("function `{}` is never declared", def.name)),
+                });
+                return;
+            }
+        };
+
+        self.return_ty = decl.return_ty.clone();
+        self.num_params = decl.params.len();
+        self.vars = decl.params.iter().chain(&def.locals).cloned().collect();
+        // The value of the body is returned if it doesn't end with a return of its own
+        match self.check_expr(&def.code) {
+            Some(ty) if decl.return_ty != Type::Unit && ty != decl.return_ty => {
+                self.errors.push(Error {
+                    line: def.line,
+                    col: def.col,
+                    msg: Some(format!(
+                        "function `{}` must return {:?}, but its body evaluates to {:?}",
+                        def.name, decl.return_ty, ty
+                    )),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn check_local(&mut self, expr: &Expr, local: u32) -> Option<Type> {
+        match self.vars.get(local as usize) {
+            Some(ty) => Some(ty.clone()),
+            None => {
+                // Locals are numbered after the parameters
+                let msg = format!(
+                    "local {} is out of range, the function has {} locals including its {} parameters",
+                    local,
+                    self.vars.len(),
+                    self.num_params
+                );
+                self.error(expr, msg);
+                None
+            }
+        }
+    }
+
+    /// Checks an expression used as an address, index or other integer operand.
+    fn check_int(&mut self, expr: &Expr) -> Option<Type> {
+        let ty = self.check_expr(expr)?;
+        if !ty.is_int() {
+            self.error(expr, format!("expected an integer, found {:?}", ty));
+            return None;
+        }
+        Some(ty)
+    }
+
+    fn check_cond(&mut self, expr: &Expr) {
+        if let Some(ty) = self.check_expr(expr) {
+            if !is_scalar(&ty) {
+                self.error(expr, format!("expected a condition, found {:?}", ty));
+            }
+        }
+    }
+
+    /// Checks an expression whose value is converted to `ty`.
+    fn check_value(&mut self, expr: &Expr, ty: &Type) {
+        if let Some(val_ty) = self.check_expr(expr) {
+            if !coerces_to(&val_ty, ty) {
+                self.error(expr, format!("expected {:?}, found {:?}", ty, val_ty));
+            }
+        }
+    }
+
+    /// Checks that the branches reaching the end of a conditional all have the same type.
+    fn check_merge(&mut self, expr: &Expr, tys: Vec<Option<Type>>) -> Option<Type> {
+        let mut tys = tys.into_iter().flatten();
+        let first = tys.next()?;
+        if let Some(ty) = tys.find(|ty| *ty != first) {
+            self.error(
+                expr,
+                format!("branches have different types {:?} and {:?}", first, ty),
+            );
+            return None;
+        }
+        Some(first)
+    }
+
+    fn check_call(&mut self, expr: &Expr, name: &str, args: &[Expr]) -> Option<Type> {
+        let decl = match self.find_decl(name) {
+            Some(decl) => decl,
+            None => {
+                self.error(expr, format!("function `{}` is never declared", name));
+                for arg in args {
+                    self.check_expr(arg);
+                }
+                return None;
+            }
+        };
+
+        let num_params = decl.params.len();
+        if args.len() < num_params || (args.len() > num_params && !decl.variadic) {
+            let at_least = if decl.variadic { "at least " } else { "" };
+            self.error(
+                expr,
+                format!(
+                    "`{}` takes {}{} arguments, found {}",
+                    name,
+                    at_least,
+                    num_params,
+                    args.len()
+                ),
+            );
+        }
+
+        for (i, arg) in args.iter().enumerate() {
+            match decl.params.get(i) {
+                Some(ty) => self.check_value(arg, ty),
+                None => {
+                    if let Some(ty) = self.check_expr(arg) {
+                        if !is_scalar(&ty) {
+                            self.error(arg, format!("can't pass {:?} as a variadic argument", ty));
+                        }
+                    }
+                }
+            }
+        }
+
+        Some(decl.return_ty.clone())
+    }
+
+    /// Checks that case values have the type of the scrutinee and are all different.
+    fn check_cases(&mut self, ty: &Type, cases: &[SwitchCase]) {
+        let mut seen = HashSet::new();
+        for case in cases {
+            let msg = if case.val.ty != *ty {
+                format!("expected a case of type {:?}, found {:?}", ty, case.val.ty)
+            } else if !seen.insert(truncate(case.val.val, ty)) {
+                format!("case {} appears more than once", case.val.val)
+            } else {
+                continue;
+            };
+            self.errors.push(Error {
+                line: case.line,
+                col: case.col,
+                msg: Some(msg),
+            });
+        }
+    }
+
+    fn check_access(&mut self, ty: &Type, access: &Access) -> Option<Type> {
+        match access {
+            Access::Load => Some(ty.clone()),
+            Access::Store(val) => {
+                self.check_value(val, ty);
+                Some(Type::Unit)
+            }
+            Access::Address => Some(Type::I64),
+        }
+    }
+
+    /// Returns the type of the expression's value, or `None` if it never produces one because it
+    /// always jumps elsewhere or already has an error.
+    fn check_expr(&mut self, expr: &Expr) -> Option<Type> {
+        match &expr.kind {
+            ExprKind::Binary(bin) => {
+                let a = self.check_expr(&bin.a);
+                let b = self.check_expr(&bin.b);
+                let ty = match (a, b) {
+                    (Some(a), Some(b)) if a != b => {
+                        self.error(
+                            expr,
+                            format!(
+                                "operands of {:?} have different types {:?} and {:?}",
+                                bin.op, a, b
+                            ),
+                        );
+                        return None;
+                    }
+                    (Some(ty), _) | (_, Some(ty)) => ty,
+                    (None, None) => return None,
+                };
+                if !is_scalar(&ty) || (ty.is_float() && !bin.op.applies_to_floats()) {
+                    self.error(expr, format!("{:?} can't be applied to {:?}", bin.op, ty));
+                    return None;
+                }
+                match &bin.ty {
+                    // Comparisons are extended to the type they name
+                    Some(ty) => Some(ty.clone()),
+                    None => Some(ty),
+                }
+            }
+            ExprKind::Unary(unary) => {
+                let ty = self.check_int(&unary.a)?;
+                match &unary.op {
+                    UnaryOp::Deref { ext: Some(_), .. } => Some(Type::I64),
+                    UnaryOp::Deref { ty, ext: None } => Some(ty.clone()),
+                    UnaryOp::Not => Some(ty),
+                }
+            }
+            ExprKind::Invoke(invoke) => self.check_call(expr, &invoke.func_name, &invoke.params),
+            ExprKind::FuncAddr(func_addr) => {
+                if self.find_decl(&func_addr.func_name).is_none() {
+                    self.error(
+                        expr,
+                        format!("function `{}` is never declared", func_addr.func_name),
+                    );
+                }
+                Some(Type::I64)
+            }
+            ExprKind::IndirectCall(call) => {
+                self.check_int(&call.callee);
+                self.check_call(expr, &call.sig_name, &call.params)
+            }
+            ExprKind::Block(block) => {
+                // Blocks have the type of their last expression, or unit if they are empty. Codegen
+                // stops at the first expression that diverges, so the block has no value after it
+                let mut ty = Some(Type::Unit);
+                for expr in &block.exprs {
+                    ty = self.check_expr(expr);
+                }
+                if block.exprs.iter().any(Expr::diverges) {
+                    None
+                } else {
+                    ty
+                }
+            }
+            ExprKind::Assignment(assign) => {
+                if let Some(ty) = self.check_local(expr, assign.local) {
+                    self.check_value(&assign.val, &ty);
+                }
+                Some(Type::Unit)
+            }
+            ExprKind::Local(local) => self.check_local(expr, local.local),
+            ExprKind::Constant(constant) => Some(constant.ty.clone()),
+            ExprKind::FloatConstant(constant) => Some(constant.ty.clone()),
+            ExprKind::Return(ret) => {
+                let return_ty = self.return_ty.clone();
+                match (&ret.val, &return_ty) {
+                    (Some(val), Type::Unit) => {
+                        self.check_expr(val);
+                        self.error(expr, "unit functions can't return a value".to_string());
+                    }
+                    (None, Type::Unit) => {}
+                    (None, ty) => {
+                        self.error(expr, format!("expected a return value of type {:?}", ty));
+                    }
+                    // Return values aren't converted, so they must match exactly
+                    (Some(val), ty) => match self.check_expr(val) {
+                        Some(val_ty) if val_ty != *ty => self.error(
+                            val,
+                            format!(
+                                "expected a return value of type {:?}, found {:?}",
+                                ty, val_ty
+                            ),
+                        ),
+                        _ => {}
+                    },
+                }
+                None
+            }
+            ExprKind::StringLit(_) => Some(Type::I64),
+            ExprKind::If(if_expr) => {
+                self.check_cond(&if_expr.cond);
+                let then_ty = self.check_expr(&if_expr.then_branch);
+                let else_ty = self.check_expr(&if_expr.else_branch);
+                self.check_merge(expr, vec![then_ty, else_ty])
+            }
+            ExprKind::Switch(switch) => {
+                if let Some(ty) = self.check_int(&switch.scrutinee) {
+                    self.check_cases(&ty, &switch.cases);
+                }
+                let mut tys: Vec<_> = switch
+                    .cases
+                    .iter()
+                    .map(|case| self.check_expr(&case.body))
+                    .collect();
+                tys.push(self.check_expr(&switch.default));
+                self.check_merge(expr, tys)
+            }
+            ExprKind::While(while_expr) => {
+                self.check_cond(&while_expr.cond);
+                self.check_expr(&while_expr.body);
+                Some(Type::Unit)
+            }
+            ExprKind::Break | ExprKind::Continue => None,
+            ExprKind::Store(store) => {
+                self.check_int(&store.addr);
+                self.check_value(&store.val, &store.ty);
+                Some(Type::Unit)
+            }
+            ExprKind::AddrOf(addr_of) => {
+                self.check_local(expr, addr_of.local)?;
+                Some(Type::I64)
+            }
+            ExprKind::GlobalAddr(_) => Some(Type::I64),
+            ExprKind::Cast(cast) => {
+                if let Some(ty) = self.check_expr(&cast.val) {
+                    if !valid_cast(&cast.op, &ty, &cast.ty) {
+                        self.error(
+                            expr,
+                            format!("can't {:?} from {:?} to {:?}", cast.op, ty, cast.ty),
+                        );
+                    }
+                }
+                Some(cast.ty.clone())
+            }
+            ExprKind::Field(field) => {
+                let def = self.find_struct(field.struct_id);
+                self.check_int(&field.addr);
+                self.check_access(&def.fields[field.field as usize], &field.access)
+            }
+            ExprKind::Index(index) => {
+                self.check_int(&index.addr);
+                self.check_int(&index.index);
+                self.check_access(&index.elem_ty, &index.access)
+            }
+            ExprKind::GlobalGet(get) => Some(self.find_global(get.global).ty.clone()),
+            ExprKind::GlobalSet(set) => {
+                let global = self.find_global(set.global);
+                self.check_value(&set.val, &global.ty);
+                Some(Type::Unit)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expr(kind: ExprKind) -> Expr {
+        Expr {
+            kind,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    fn constant(ty: Type, val: i64) -> Expr {
+        expr(ExprKind::Constant(ConstantExpr { ty, val }))
+    }
+
+    fn block(exprs: Vec<Expr>) -> Expr {
+        expr(ExprKind::Block(BlockExpr { exprs }))
+    }
+
+    fn ret(val: Expr) -> Expr {
+        expr(ExprKind::Return(ReturnExpr {
+            val: Some(Box::new(val)),
+        }))
+    }
+
+    /// Checks a function `f` returning i32 with the body `code`.
+    fn check_body(code: Expr) -> Vec<Error> {
+        let items = [
+            Item::FunctionDecl(FunctionDeclItem {
+                name: "f".to_string(),
+                return_ty: Type::I32,
+                params: Vec::new(),
+                linkage: Linkage::External,
+                variadic: false,
+            }),
+            Item::FunctionDef(FunctionDefItem {
+                name: "f".to_string(),
+                locals: Vec::new(),
+                code,
+                line: 1,
+                col: 1,
+            }),
+        ];
+        check(&items)
+    }
+
+    #[test]
+    fn nested_return_ends_block() {
+        // `{ { return 0i32 } 1i64 }` never evaluates to the i64, so codegen must not return it
+        let code = block(vec![
+            block(vec![ret(constant(Type::I32, 0))]),
+            constant(Type::I64, 1),
+        ]);
+        assert!(code.diverges());
+        assert!(check_body(code).is_empty());
+    }
+
+    #[test]
+    fn diverging_if_ends_block() {
+        let if_expr = expr(ExprKind::If(IfExpr {
+            cond: Box::new(constant(Type::I32, 1)),
+            then_branch: Box::new(ret(constant(Type::I32, 0))),
+            else_branch: Box::new(ret(constant(Type::I32, 1))),
+        }));
+        let code = block(vec![if_expr, constant(Type::I64, 1)]);
+        assert!(code.diverges());
+        assert!(check_body(code).is_empty());
+    }
+
+    #[test]
+    fn block_value_must_match_return_type() {
+        let code = block(vec![constant(Type::I32, 0), constant(Type::I64, 1)]);
+        assert!(!code.diverges());
+        assert_eq!(check_body(code).len(), 1);
+    }
+}
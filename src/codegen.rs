@@ -1,22 +1,38 @@
 use crate::ast::{self};
-use inkwell::builder::Builder;
+use inkwell::basic_block::BasicBlock;
+use inkwell::builder::{Builder, BuilderError};
 use inkwell::context::Context;
 use inkwell::module::{Linkage, Module};
 use inkwell::targets::{
     CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine,
 };
-use inkwell::types::{BasicMetadataTypeEnum, IntType};
-use inkwell::values::{BasicMetadataValueEnum, FunctionValue, IntValue, PointerValue};
-use inkwell::{AddressSpace, OptimizationLevel};
+use inkwell::types::{
+    BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FunctionType, IntType, StructType,
+};
+use inkwell::values::{
+    BasicMetadataValueEnum, BasicValueEnum, FloatValue, FunctionValue, GlobalValue,
+    InstructionOpcode, IntValue, PointerValue,
+};
+use inkwell::{FloatPredicate, IntPredicate, OptimizationLevel};
+use std::collections::HashMap;
 use std::path::Path;
 
+struct Loop<'ctx> {
+    header: BasicBlock<'ctx>,
+    exit: BasicBlock<'ctx>,
+}
+
 pub struct Codegen<'ctx> {
     context: &'ctx Context,
     module: Module<'ctx>,
     builder: Builder<'ctx>,
 
     cur_function: Option<FunctionValue<'ctx>>,
-    cur_vars: Vec<PointerValue<'ctx>>,
+    cur_vars: Vec<(PointerValue<'ctx>, BasicTypeEnum<'ctx>)>,
+    loops: Vec<Loop<'ctx>>,
+    globals: Vec<(GlobalValue<'ctx>, BasicTypeEnum<'ctx>)>,
+    structs: Vec<StructType<'ctx>>,
+    strings: HashMap<String, GlobalValue<'ctx>>,
 }
 
 impl<'ctx> Codegen<'ctx> {
@@ -30,19 +46,36 @@ impl<'ctx> Codegen<'ctx> {
 
             cur_function: None,
             cur_vars: Vec::new(),
+            loops: Vec::new(),
+            globals: Vec::new(),
+            structs: Vec::new(),
+            strings: HashMap::new(),
         };
 
+        // Everything is declared before any bodies are built so functions can be used before the
+        // item declaring them
+        let mut defs = Vec::new();
         for item in ast {
             match item {
                 ast::Item::FunctionDecl(decl) => {
                     codegen.decl_function(decl);
                 }
                 ast::Item::FunctionDef(def) => {
-                    codegen.define_function(def);
+                    defs.push(def);
+                }
+                ast::Item::Global(global) => {
+                    codegen.define_global(global);
+                }
+                ast::Item::Struct(def) => {
+                    codegen.define_struct(def);
                 }
             }
         }
 
+        for def in defs {
+            codegen.define_function(def);
+        }
+
         codegen.write_object(output_path);
     }
 
@@ -72,22 +105,46 @@ impl<'ctx> Codegen<'ctx> {
     }
 
     fn decl_function(&self, decl: ast::FunctionDeclItem) {
-        let ret_type = self.get_type_from_type(decl.return_ty);
         let param_types: Vec<BasicMetadataTypeEnum> = decl
             .params
             .into_iter()
-            .map(|p| BasicMetadataTypeEnum::IntType(self.get_type_from_type(p)))
+            .map(|p| self.get_basic_type(p).into())
             .collect();
 
-        let fn_type = ret_type.fn_type(&param_types, false);
-        self.module.add_function(
-            &decl.name,
-            fn_type,
-            Some(match decl.linkage {
-                ast::Linkage::External => Linkage::External,
-                ast::Linkage::Internal => Linkage::Internal,
-            }),
-        );
+        let fn_type = match decl.return_ty {
+            ast::Type::Unit => self
+                .context
+                .void_type()
+                .fn_type(&param_types, decl.variadic),
+            ty => self.get_basic_type(ty).fn_type(&param_types, decl.variadic),
+        };
+        self.module
+            .add_function(&decl.name, fn_type, Some(get_linkage(decl.linkage)));
+    }
+
+    fn define_global(&mut self, global: ast::GlobalItem) {
+        let ty = self.get_basic_type(global.ty);
+        let global_val = self.module.add_global(ty, None, &global.name);
+        global_val.set_constant(!global.mutable);
+        match (global.init, &global.linkage) {
+            (Some(init), _) => global_val.set_initializer(&self.build_const(init.kind)),
+            // Internal globals must be defined here, external ones may come from another module
+            (None, ast::Linkage::Internal) => global_val.set_initializer(&ty.const_zero()),
+            (None, ast::Linkage::External) => {}
+        }
+        global_val.set_linkage(get_linkage(global.linkage));
+        self.globals.push((global_val, ty));
+    }
+
+    fn define_struct(&mut self, def: ast::StructItem) {
+        let struct_type = self.context.opaque_struct_type(&def.name);
+        let field_types: Vec<BasicTypeEnum> = def
+            .fields
+            .into_iter()
+            .map(|field| self.get_basic_type(field))
+            .collect();
+        struct_type.set_body(&field_types, false);
+        self.structs.push(struct_type);
     }
 
     fn define_function(&mut self, def: ast::FunctionDefItem) {
@@ -98,20 +155,30 @@ impl<'ctx> Codegen<'ctx> {
         self.builder.position_at_end(entry);
 
         for arg in func_val.get_param_iter() {
-            let alloca = self.builder.build_alloca(arg.get_type(), "").unwrap();
+            let ty = arg.get_type();
+            let alloca = self.builder.build_alloca(ty, "").unwrap();
             self.builder.build_store(alloca, arg).unwrap();
-            self.cur_vars.push(alloca);
+            self.cur_vars.push((alloca, ty));
         }
 
         for local in def.locals {
-            let alloca = self
-                .builder
-                .build_alloca(self.get_type_from_type(local), "")
-                .unwrap();
-            self.cur_vars.push(alloca);
+            let ty = self.get_basic_type(local);
+            let alloca = self.builder.build_alloca(ty, "").unwrap();
+            self.cur_vars.push((alloca, ty));
         }
 
-        self.build_expr(def.code);
+        let val = self.build_expr(def.code);
+
+        // Falling off the end of the body returns its value, unless the end can't be reached and
+        // the value may not even have the return type
+        let end = self.builder.get_insert_block().unwrap();
+        match (func_val.get_type().get_return_type(), val) {
+            _ if self.is_unreachable(end) => self.builder.build_unreachable().unwrap(),
+            (None, _) => self.builder.build_return(None).unwrap(),
+            (Some(_), Some(val)) => self.builder.build_return(Some(&val)).unwrap(),
+            // The checker only lets bodies without a value end in unreachable code
+            (Some(_), None) => self.builder.build_unreachable().unwrap(),
+        };
 
         if func_val.verify(true) {
         } else {
@@ -136,101 +203,597 @@ impl<'ctx> Codegen<'ctx> {
         }
     }
 
+    fn get_basic_type(&self, ty: ast::Type) -> BasicTypeEnum<'ctx> {
+        match ty {
+            ast::Type::F32 => self.context.f32_type().into(),
+            ast::Type::F64 => self.context.f64_type().into(),
+            ast::Type::Struct(id) => self.structs[id as usize].into(),
+            ast::Type::Array(elem, len) => self.get_basic_type(*elem).array_type(len).into(),
+            ty => self.get_type_from_type(ty).into(),
+        }
+    }
+
     fn find_function(&self, name: &str) -> FunctionValue<'ctx> {
         self.module.get_function(name).unwrap()
     }
 
-    fn build_expr(&mut self, expr: ast::Expr) -> IntValue<'ctx> {
-        match expr {
-            ast::Expr::Binary(expr) => {
-                let a = self.build_expr(*expr.a);
-                let b = self.build_expr(*expr.b);
-                match expr.op {
-                    ast::BinaryOp::Add => self.builder.build_int_add(a, b, ""),
-                    ast::BinaryOp::Sub => self.builder.build_int_sub(a, b, ""),
-                    ast::BinaryOp::Mult => self.builder.build_int_mul(a, b, ""),
-                    ast::BinaryOp::Div => self.builder.build_int_signed_div(a, b, ""),
-                    ast::BinaryOp::Lsh => self.builder.build_left_shift(a, b, ""),
-                    ast::BinaryOp::Rsh => self.builder.build_right_shift(a, b, true, ""),
+    /// Starts a new block for the code following a terminator. Nothing branches to it, so it only
+    /// holds unreachable code.
+    fn start_unreachable_block(&self) {
+        let block = self
+            .context
+            .append_basic_block(self.cur_function.unwrap(), "unreachable");
+        self.builder.position_at_end(block);
+    }
+
+    fn is_unreachable(&self, block: BasicBlock<'ctx>) -> bool {
+        let entry = self.cur_function.unwrap().get_first_basic_block().unwrap();
+        block != entry && block.get_first_use().is_none()
+    }
+
+    /// Branches to `dest` unless the current block is unreachable, returning the block the branch
+    /// was made from.
+    fn branch_to(&self, dest: BasicBlock<'ctx>) -> Option<BasicBlock<'ctx>> {
+        let block = self.builder.get_insert_block().unwrap();
+        if self.is_unreachable(block) {
+            self.builder.build_unreachable().unwrap();
+            return None;
+        }
+        self.builder.build_unconditional_branch(dest).unwrap();
+        Some(block)
+    }
+
+    /// Joins the values of the branches that reach the current block, which are either all unit or
+    /// all of the same type.
+    fn build_merge(
+        &self,
+        incoming: &[(Option<BasicValueEnum<'ctx>>, BasicBlock<'ctx>)],
+    ) -> Option<BasicValueEnum<'ctx>> {
+        match incoming {
+            [] | [(None, _), ..] => None,
+            // Only one branch reaches the block, so its value can be used directly
+            [(val, _)] => *val,
+            [(Some(first), _), ..] => {
+                let phi = self.builder.build_phi(first.get_type(), "").unwrap();
+                for (val, block) in incoming {
+                    phi.add_incoming(&[(&val.unwrap(), *block)]);
                 }
+                Some(phi.as_basic_value())
+            }
+        }
+    }
+
+    fn build_compare(
+        &self,
+        predicate: IntPredicate,
+        a: IntValue<'ctx>,
+        b: IntValue<'ctx>,
+        ty: Option<ast::Type>,
+    ) -> Result<IntValue<'ctx>, BuilderError> {
+        let ty = self.get_type_from_type(ty.unwrap());
+        let cmp = self.builder.build_int_compare(predicate, a, b, "")?;
+        self.builder.build_int_z_extend(cmp, ty, "")
+    }
+
+    fn build_float_compare(
+        &self,
+        predicate: FloatPredicate,
+        a: FloatValue<'ctx>,
+        b: FloatValue<'ctx>,
+        ty: Option<ast::Type>,
+    ) -> Result<IntValue<'ctx>, BuilderError> {
+        let ty = self.get_type_from_type(ty.unwrap());
+        let cmp = self.builder.build_float_compare(predicate, a, b, "")?;
+        self.builder.build_int_z_extend(cmp, ty, "")
+    }
+
+    fn build_int_binary(
+        &self,
+        op: ast::BinaryOp,
+        a: IntValue<'ctx>,
+        b: IntValue<'ctx>,
+        ty: Option<ast::Type>,
+    ) -> IntValue<'ctx> {
+        match op {
+            ast::BinaryOp::Add => self.builder.build_int_add(a, b, ""),
+            ast::BinaryOp::Sub => self.builder.build_int_sub(a, b, ""),
+            ast::BinaryOp::Mult => self.builder.build_int_mul(a, b, ""),
+            ast::BinaryOp::Div => self.builder.build_int_signed_div(a, b, ""),
+            ast::BinaryOp::Lsh => self.builder.build_left_shift(a, b, ""),
+            ast::BinaryOp::Rsh => self.builder.build_right_shift(a, b, true, ""),
+            ast::BinaryOp::Eq => self.build_compare(IntPredicate::EQ, a, b, ty),
+            ast::BinaryOp::Ne => self.build_compare(IntPredicate::NE, a, b, ty),
+            ast::BinaryOp::SLt => self.build_compare(IntPredicate::SLT, a, b, ty),
+            ast::BinaryOp::SLe => self.build_compare(IntPredicate::SLE, a, b, ty),
+            ast::BinaryOp::SGt => self.build_compare(IntPredicate::SGT, a, b, ty),
+            ast::BinaryOp::SGe => self.build_compare(IntPredicate::SGE, a, b, ty),
+            ast::BinaryOp::ULt => self.build_compare(IntPredicate::ULT, a, b, ty),
+            ast::BinaryOp::ULe => self.build_compare(IntPredicate::ULE, a, b, ty),
+            ast::BinaryOp::UGt => self.build_compare(IntPredicate::UGT, a, b, ty),
+            ast::BinaryOp::UGe => self.build_compare(IntPredicate::UGE, a, b, ty),
+            ast::BinaryOp::And => self.builder.build_and(a, b, ""),
+            ast::BinaryOp::Or => self.builder.build_or(a, b, ""),
+            ast::BinaryOp::Xor => self.builder.build_xor(a, b, ""),
+            ast::BinaryOp::SRem => self.builder.build_int_signed_rem(a, b, ""),
+            ast::BinaryOp::URem => self.builder.build_int_unsigned_rem(a, b, ""),
+            ast::BinaryOp::UDiv => self.builder.build_int_unsigned_div(a, b, ""),
+            ast::BinaryOp::URsh => self.builder.build_right_shift(a, b, false, ""),
+        }
+        .unwrap()
+    }
+
+    /// Signed comparisons of floats are ordered and unsigned ones are unordered.
+    fn build_float_binary(
+        &self,
+        op: ast::BinaryOp,
+        a: FloatValue<'ctx>,
+        b: FloatValue<'ctx>,
+        ty: Option<ast::Type>,
+    ) -> BasicValueEnum<'ctx> {
+        let val: Result<BasicValueEnum<'ctx>, BuilderError> = match op {
+            ast::BinaryOp::Add => self.builder.build_float_add(a, b, "").map(Into::into),
+            ast::BinaryOp::Sub => self.builder.build_float_sub(a, b, "").map(Into::into),
+            ast::BinaryOp::Mult => self.builder.build_float_mul(a, b, "").map(Into::into),
+            ast::BinaryOp::Div => self.builder.build_float_div(a, b, "").map(Into::into),
+            ast::BinaryOp::SRem => self.builder.build_float_rem(a, b, "").map(Into::into),
+            ast::BinaryOp::Eq => self
+                .build_float_compare(FloatPredicate::OEQ, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::Ne => self
+                .build_float_compare(FloatPredicate::UNE, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::SLt => self
+                .build_float_compare(FloatPredicate::OLT, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::SLe => self
+                .build_float_compare(FloatPredicate::OLE, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::SGt => self
+                .build_float_compare(FloatPredicate::OGT, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::SGe => self
+                .build_float_compare(FloatPredicate::OGE, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::ULt => self
+                .build_float_compare(FloatPredicate::ULT, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::ULe => self
+                .build_float_compare(FloatPredicate::ULE, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::UGt => self
+                .build_float_compare(FloatPredicate::UGT, a, b, ty)
+                .map(Into::into),
+            ast::BinaryOp::UGe => self
+                .build_float_compare(FloatPredicate::UGE, a, b, ty)
+                .map(Into::into),
+            // Bitwise operators, shifts and unsigned arithmetic have no float equivalent
+            _ => panic!("stack bad"),
+        };
+        val.unwrap()
+    }
+
+    /// Converts `val` to `ty` between integers of different widths or floats of different
+    /// precisions, leaving it untouched if the types already match.
+    fn coerce(&self, val: BasicValueEnum<'ctx>, ty: BasicTypeEnum<'ctx>) -> BasicValueEnum<'ctx> {
+        match (val, ty) {
+            (BasicValueEnum::IntValue(val), BasicTypeEnum::IntType(ty)) => self
+                .builder
+                .build_int_cast_sign_flag(val, ty, true, "")
                 .unwrap()
+                .into(),
+            (BasicValueEnum::FloatValue(val), BasicTypeEnum::FloatType(ty)) => {
+                self.builder.build_float_cast(val, ty, "").unwrap().into()
+            }
+            (val, _) => val,
+        }
+    }
+
+    fn build_const(&self, kind: ast::ExprKind) -> BasicValueEnum<'ctx> {
+        match kind {
+            ast::ExprKind::Constant(expr) => {
+                let ty = self.get_type_from_type(expr.ty);
+                ty.const_int(expr.val as u64, true).into()
+            }
+            ast::ExprKind::FloatConstant(expr) => {
+                let ty = self.get_basic_type(expr.ty).into_float_type();
+                ty.const_float(expr.val).into()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn build_access(
+        &mut self,
+        ptr: PointerValue<'ctx>,
+        ty: BasicTypeEnum<'ctx>,
+        access: ast::Access,
+    ) -> Option<BasicValueEnum<'ctx>> {
+        match access {
+            ast::Access::Load => Some(self.builder.build_load(ty, ptr, "").unwrap()),
+            ast::Access::Store(val) => {
+                let val = self.build_expr(*val)?;
+                let val = self.coerce(val, ty);
+                self.builder.build_store(ptr, val).unwrap();
+                None
+            }
+            ast::Access::Address => Some(
+                self.builder
+                    .build_ptr_to_int(ptr, self.context.i64_type(), "")
+                    .unwrap()
+                    .into(),
+            ),
+        }
+    }
+
+    fn build_cond(&mut self, expr: ast::Expr) -> Option<IntValue<'ctx>> {
+        let cond = match self.build_expr(expr)? {
+            BasicValueEnum::FloatValue(val) => {
+                let zero = val.get_type().const_zero();
+                self.builder
+                    .build_float_compare(FloatPredicate::ONE, val, zero, "")
+                    .unwrap()
+            }
+            val => {
+                let val = val.into_int_value();
+                let zero = val.get_type().const_zero();
+                self.builder
+                    .build_int_compare(IntPredicate::NE, val, zero, "")
+                    .unwrap()
             }
-            ast::Expr::Unary(expr) => {
-                let a = self.build_expr(*expr.a);
-                match expr.op {
-                    ast::UnaryOp::Deref => {
-                        let ptr_type = self.context.i32_type().ptr_type(Default::default());
+        };
+        Some(cond)
+    }
+
+    fn build_args(
+        &mut self,
+        fn_type: FunctionType<'ctx>,
+        params: Vec<ast::Expr>,
+    ) -> Option<Vec<BasicMetadataValueEnum<'ctx>>> {
+        let param_types = fn_type.get_param_types();
+        params
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let val = self.build_expr(p)?;
+                let val = match (param_types.get(i), val) {
+                    (Some(&ty), val) => self.coerce(val, ty),
+                    // Variadic arguments follow C's default argument promotions
+                    (None, BasicValueEnum::IntValue(int))
+                        if int.get_type().get_bit_width() < 32 =>
+                    {
+                        self.coerce(val, self.context.i32_type().into())
+                    }
+                    (None, BasicValueEnum::FloatValue(_)) => {
+                        self.coerce(val, self.context.f64_type().into())
+                    }
+                    (None, val) => val,
+                };
+                Some(val.into())
+            })
+            .collect()
+    }
+
+    fn build_int_expr(&mut self, expr: ast::Expr) -> Option<IntValue<'ctx>> {
+        Some(self.build_expr(expr)?.into_int_value())
+    }
+
+    /// Builds an expression, returning its value or `None` if it is unit or never produces one. An
+    /// operand that never produces a value makes the rest of the expression unreachable, so it isn't
+    /// built.
+    fn build_expr(&mut self, expr: ast::Expr) -> Option<BasicValueEnum<'ctx>> {
+        match expr.kind {
+            ast::ExprKind::Binary(expr) => {
+                let a = self.build_expr(*expr.a)?;
+                let b = self.build_expr(*expr.b)?;
+                Some(match (a, b) {
+                    (BasicValueEnum::FloatValue(a), BasicValueEnum::FloatValue(b)) => {
+                        self.build_float_binary(expr.op, a, b, expr.ty)
+                    }
+                    (a, b) => self
+                        .build_int_binary(expr.op, a.into_int_value(), b.into_int_value(), expr.ty)
+                        .into(),
+                })
+            }
+            ast::ExprKind::Unary(expr) => {
+                let a = self.build_int_expr(*expr.a)?;
+                Some(match expr.op {
+                    ast::UnaryOp::Deref { ty, ext } => {
+                        let ty = self.get_basic_type(ty);
+                        let ptr_type = ty.ptr_type(Default::default());
                         let ptr = self.builder.build_int_to_ptr(a, ptr_type, "").unwrap();
-                        self.builder
-                            .build_load(ptr_type, ptr, "")
-                            .unwrap()
-                            .into_int_value()
+                        let val = self.builder.build_load(ty, ptr, "").unwrap();
+                        let i64_type = self.context.i64_type();
+                        match ext {
+                            Some(ast::Extension::Sign) => self
+                                .builder
+                                .build_int_s_extend_or_bit_cast(val.into_int_value(), i64_type, "")
+                                .unwrap()
+                                .into(),
+                            Some(ast::Extension::Zero) => self
+                                .builder
+                                .build_int_z_extend_or_bit_cast(val.into_int_value(), i64_type, "")
+                                .unwrap()
+                                .into(),
+                            None => val,
+                        }
                     }
-                    ast::UnaryOp::Not => self.builder.build_not(a, "").unwrap(),
-                }
+                    ast::UnaryOp::Not => self.builder.build_not(a, "").unwrap().into(),
+                })
             }
-            ast::Expr::Invoke(expr) => {
+            ast::ExprKind::Invoke(expr) => {
                 let fn_val = self.module.get_function(&expr.func_name).unwrap();
-                let args: Vec<BasicMetadataValueEnum> = expr
-                    .params
-                    .into_iter()
-                    .map(|p| BasicMetadataValueEnum::IntValue(self.build_expr(p)))
-                    .collect();
-                let val = self
+                let args = self.build_args(fn_val.get_type(), expr.params)?;
+                let call = self.builder.build_call(fn_val, &args, "").unwrap();
+                // Calls to unit functions have no value
+                call.try_as_basic_value().left()
+            }
+            ast::ExprKind::FuncAddr(expr) => {
+                let fn_val = self.module.get_function(&expr.func_name).unwrap();
+                let ptr = fn_val.as_global_value().as_pointer_value();
+                Some(
+                    self.builder
+                        .build_ptr_to_int(ptr, self.context.i64_type(), "")
+                        .unwrap()
+                        .into(),
+                )
+            }
+            ast::ExprKind::IndirectCall(expr) => {
+                let fn_type = self.module.get_function(&expr.sig_name).unwrap().get_type();
+                let callee = self.build_int_expr(*expr.callee)?;
+                let ptr_type = fn_type.ptr_type(Default::default());
+                let ptr = self.builder.build_int_to_ptr(callee, ptr_type, "").unwrap();
+                let args = self.build_args(fn_type, expr.params)?;
+                let call = self
                     .builder
-                    .build_call(fn_val, &args, "")
-                    .unwrap()
-                    .try_as_basic_value()
-                    .left()
+                    .build_indirect_call(fn_type, ptr, &args, "")
                     .unwrap();
-                val.into_int_value()
+                call.try_as_basic_value().left()
             }
-            ast::Expr::Block(expr) => {
+            ast::ExprKind::Block(expr) => {
+                // Blocks evaluate to their last expression, or unit if they are empty. Like the
+                // checker, nothing after an expression that diverges is built and there is no value
+                let mut val = None;
                 for expr in expr.exprs {
-                    self.build_expr(expr);
+                    if expr.diverges() {
+                        self.build_expr(expr);
+                        return None;
+                    }
+                    val = self.build_expr(expr);
+                }
+                val
+            }
+            ast::ExprKind::Assignment(expr) => {
+                let val = self.build_expr(*expr.val)?;
+                let (pointer_val, ty) = self.cur_vars[expr.local as usize];
+                let val = self.coerce(val, ty);
+                self.builder.build_store(pointer_val, val).unwrap();
+                None
+            }
+            ast::ExprKind::Local(expr) => {
+                let (pointer_val, ty) = self.cur_vars[expr.local as usize];
+                Some(self.builder.build_load(ty, pointer_val, "").unwrap())
+            }
+            kind @ (ast::ExprKind::Constant(_) | ast::ExprKind::FloatConstant(_)) => {
+                Some(self.build_const(kind))
+            }
+            ast::ExprKind::Return(expr) => {
+                match expr.val {
+                    Some(val) => {
+                        let val = self.build_expr(*val)?;
+                        self.builder.build_return(Some(&val)).unwrap();
+                    }
+                    None => {
+                        self.builder.build_return(None).unwrap();
+                    }
                 }
-                self.context.i64_type().const_int(0, false)
+                self.start_unreachable_block();
+                None
+            }
+            ast::ExprKind::StringLit(expr) => {
+                let global = match self.strings.get(&expr.str) {
+                    Some(&global) => global,
+                    None => {
+                        let val = self.context.const_string(expr.str.as_bytes(), true);
+                        let global = self.module.add_global(val.get_type(), None, "");
+                        global.set_initializer(&val);
+                        global.set_constant(true);
+                        global.set_linkage(Linkage::Private);
+                        global.set_unnamed_addr(true);
+                        self.strings.insert(expr.str, global);
+                        global
+                    }
+                };
+                let ptr = global.as_pointer_value();
+                Some(
+                    self.builder
+                        .build_ptr_to_int(ptr, self.context.i64_type(), "")
+                        .unwrap()
+                        .into(),
+                )
+            }
+            ast::ExprKind::If(expr) => {
+                let func_val = self.cur_function.unwrap();
+                let cond = self.build_cond(*expr.cond)?;
+                let then_block = self.context.append_basic_block(func_val, "then");
+                let else_block = self.context.append_basic_block(func_val, "else");
+                let merge_block = self.context.append_basic_block(func_val, "merge");
+                self.builder
+                    .build_conditional_branch(cond, then_block, else_block)
+                    .unwrap();
+
+                self.builder.position_at_end(then_block);
+                let then_val = self.build_expr(*expr.then_branch);
+                let then_incoming = self.branch_to(merge_block).map(|block| (then_val, block));
+
+                self.builder.position_at_end(else_block);
+                let else_val = self.build_expr(*expr.else_branch);
+                let else_incoming = self.branch_to(merge_block).map(|block| (else_val, block));
+
+                self.builder.position_at_end(merge_block);
+                let incoming: Vec<_> = then_incoming.into_iter().chain(else_incoming).collect();
+                self.build_merge(&incoming)
             }
-            ast::Expr::Assignment(expr) => {
-                let val = self.build_expr(*expr.val);
+            ast::ExprKind::Switch(expr) => {
+                let func_val = self.cur_function.unwrap();
+                let scrutinee = self.build_int_expr(*expr.scrutinee)?;
+                let default_block = self.context.append_basic_block(func_val, "switch_default");
+                let merge_block = self.context.append_basic_block(func_val, "switch_merge");
+                let case_blocks: Vec<_> = expr
+                    .cases
+                    .iter()
+                    .map(|case| {
+                        // Case values take on the width of the scrutinee
+                        let val = scrutinee.get_type().const_int(case.val.val as u64, true);
+                        let block = self.context.append_basic_block(func_val, "switch_case");
+                        (val, block)
+                    })
+                    .collect();
                 self.builder
-                    .build_store(self.cur_vars[expr.local as usize], val)
+                    .build_switch(scrutinee, default_block, &case_blocks)
                     .unwrap();
-                self.context.i64_type().const_int(0, false)
+
+                let mut incoming = Vec::new();
+                for (case, (_, block)) in expr.cases.into_iter().zip(case_blocks) {
+                    self.builder.position_at_end(block);
+                    let val = self.build_expr(case.body);
+                    incoming.extend(self.branch_to(merge_block).map(|block| (val, block)));
+                }
+
+                self.builder.position_at_end(default_block);
+                let val = self.build_expr(*expr.default);
+                incoming.extend(self.branch_to(merge_block).map(|block| (val, block)));
+
+                self.builder.position_at_end(merge_block);
+                self.build_merge(&incoming)
             }
-            ast::Expr::Local(expr) => {
-                let pointer_val = self.cur_vars[expr.local as usize];
+            ast::ExprKind::While(expr) => {
+                let func_val = self.cur_function.unwrap();
+                let header = self.context.append_basic_block(func_val, "loop_header");
+                self.builder.build_unconditional_branch(header).unwrap();
+
+                self.builder.position_at_end(header);
+                let cond = self.build_cond(*expr.cond)?;
+                let body = self.context.append_basic_block(func_val, "loop_body");
+                let exit = self.context.append_basic_block(func_val, "loop_exit");
                 self.builder
-                    .build_load(pointer_val.get_type(), pointer_val, "")
-                    .unwrap()
-                    .into_int_value()
+                    .build_conditional_branch(cond, body, exit)
+                    .unwrap();
+
+                self.builder.position_at_end(body);
+                self.loops.push(Loop { header, exit });
+                self.build_expr(*expr.body);
+                self.loops.pop();
+                self.branch_to(header);
+
+                self.builder.position_at_end(exit);
+                None
             }
-            ast::Expr::Constant(expr) => {
-                let ty = self.get_type_from_type(expr.ty);
-                ty.const_int(expr.val as u64, false)
-            }
-            ast::Expr::Return(expr) => {
-                let val = self.build_expr(*expr.val);
-                self.builder.build_return(Some(&val)).unwrap();
-                self.context.i64_type().const_int(0, false)
-            }
-            ast::Expr::StringLit(expr) => {
-                let elem_type = self.context.i8_type();
-                let ty = elem_type.array_type(expr.str.len() as u32);
-                // const address space
-                let global = self.module.add_global(ty, Some(AddressSpace::from(4)), "");
-                let items: Vec<_> = expr
-                    .str
-                    .bytes()
-                    .map(|b| elem_type.const_int(b as u64, false))
-                    .collect();
-                let val = elem_type.const_array(&items);
-                global.set_initializer(&val);
-                let ptr = global.as_pointer_value();
+            ast::ExprKind::Break => {
+                let exit = self.loops.last().unwrap().exit;
+                self.builder.build_unconditional_branch(exit).unwrap();
+                self.start_unreachable_block();
+                None
+            }
+            ast::ExprKind::Continue => {
+                let header = self.loops.last().unwrap().header;
+                self.builder.build_unconditional_branch(header).unwrap();
+                self.start_unreachable_block();
+                None
+            }
+            ast::ExprKind::Store(expr) => {
+                let ty = self.get_basic_type(expr.ty);
+                let addr = self.build_int_expr(*expr.addr)?;
+                let val = self.build_expr(*expr.val)?;
+                let ptr_type = ty.ptr_type(Default::default());
+                let ptr = self.builder.build_int_to_ptr(addr, ptr_type, "").unwrap();
+                let val = self.coerce(val, ty);
+                self.builder.build_store(ptr, val).unwrap();
+                None
+            }
+            ast::ExprKind::AddrOf(expr) => {
+                let (pointer_val, _) = self.cur_vars[expr.local as usize];
+                Some(
+                    self.builder
+                        .build_ptr_to_int(pointer_val, self.context.i64_type(), "")
+                        .unwrap()
+                        .into(),
+                )
+            }
+            ast::ExprKind::GlobalAddr(expr) => {
+                let (global_val, _) = self.globals[expr.global as usize];
+                let ptr = global_val.as_pointer_value();
+                Some(
+                    self.builder
+                        .build_ptr_to_int(ptr, self.context.i64_type(), "")
+                        .unwrap()
+                        .into(),
+                )
+            }
+            ast::ExprKind::Cast(expr) => {
+                let ty = self.get_basic_type(expr.ty);
+                let val = self.build_expr(*expr.val)?;
+                let op = match expr.op {
+                    ast::CastOp::SExt => InstructionOpcode::SExt,
+                    ast::CastOp::ZExt => InstructionOpcode::ZExt,
+                    ast::CastOp::Trunc => InstructionOpcode::Trunc,
+                    ast::CastOp::SIToFP => InstructionOpcode::SIToFP,
+                    ast::CastOp::UIToFP => InstructionOpcode::UIToFP,
+                    ast::CastOp::FPToSI => InstructionOpcode::FPToSI,
+                    ast::CastOp::FPToUI => InstructionOpcode::FPToUI,
+                    ast::CastOp::FPExt => InstructionOpcode::FPExt,
+                    ast::CastOp::FPTrunc => InstructionOpcode::FPTrunc,
+                };
+                Some(self.builder.build_cast(op, val, ty, "").unwrap())
+            }
+            ast::ExprKind::Field(expr) => {
+                let struct_type = self.structs[expr.struct_id as usize];
+                let addr = self.build_int_expr(*expr.addr)?;
+                let ptr_type = struct_type.ptr_type(Default::default());
+                let ptr = self.builder.build_int_to_ptr(addr, ptr_type, "").unwrap();
+                let field_ptr = self
+                    .builder
+                    .build_struct_gep(struct_type, ptr, expr.field, "")
+                    .unwrap();
+                let field_type = struct_type.get_field_type_at_index(expr.field).unwrap();
+                self.build_access(field_ptr, field_type, expr.access)
+            }
+            ast::ExprKind::Index(expr) => {
+                let elem_type = self.get_basic_type(expr.elem_ty);
+                let addr = self.build_int_expr(*expr.addr)?;
+                let index = self.build_int_expr(*expr.index)?;
+                let ptr_type = elem_type.ptr_type(Default::default());
+                let ptr = self.builder.build_int_to_ptr(addr, ptr_type, "").unwrap();
+                let elem_ptr = unsafe {
+                    self.builder
+                        .build_gep(elem_type, ptr, &[index], "")
+                        .unwrap()
+                };
+                self.build_access(elem_ptr, elem_type, expr.access)
+            }
+            ast::ExprKind::GlobalGet(expr) => {
+                let (global_val, ty) = self.globals[expr.global as usize];
+                Some(
+                    self.builder
+                        .build_load(ty, global_val.as_pointer_value(), "")
+                        .unwrap(),
+                )
+            }
+            ast::ExprKind::GlobalSet(expr) => {
+                let (global_val, ty) = self.globals[expr.global as usize];
+                let val = self.build_expr(*expr.val)?;
+                let val = self.coerce(val, ty);
                 self.builder
-                    .build_ptr_to_int(ptr, self.context.i64_type(), "")
-                    .unwrap()
+                    .build_store(global_val.as_pointer_value(), val)
+                    .unwrap();
+                None
             }
         }
     }
 }
+
+fn get_linkage(linkage: ast::Linkage) -> Linkage {
+    match linkage {
+        ast::Linkage::External => Linkage::External,
+        ast::Linkage::Internal => Linkage::Internal,
+    }
+}
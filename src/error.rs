@@ -4,11 +4,16 @@ use std::fmt;
 pub struct Error {
     pub line: usize,
     pub col: usize,
+    /// What went wrong, for errors found after parsing.
+    pub msg: Option<String>,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "stack bad")
+        match &self.msg {
+            Some(msg) => write!(fmt, "stack bad: {}", msg),
+            None => write!(fmt, "stack bad"),
+        }
     }
 }
 
@@ -96,6 +96,7 @@ impl<'a> Lexer<'a> {
         Error {
             line: self.line,
             col: self.col,
+            msg: None,
         }
     }
 
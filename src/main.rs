@@ -1,20 +1,22 @@
 mod ast;
+mod checker;
 mod cli;
 mod codegen;
 mod error;
 mod lexer;
+mod optimizer;
 mod parser;
 mod token;
 
 use codegen::Codegen;
-use error::Result;
+use error::{Error, Result};
 use lexer::Lexer;
 use parser::Parser;
 use std::fs;
 use std::path::Path;
 use token::TokenPair;
 
-fn compile(src: &str, output_path: &str) -> Result<()> {
+fn parse(src: &str) -> Result<Vec<ast::Item>> {
     let tokens = Lexer::new(src).lex()?;
     let pairs: Result<Vec<TokenPair>> = tokens
         .chunks(2)
@@ -27,8 +29,17 @@ fn compile(src: &str, output_path: &str) -> Result<()> {
         [0, 0, 0, 0, 0, 0, 0, 0] => {}
         _ => return Err(magic.error()),
     }
-    let ast = Parser::new(pairs).parse()?;
+    Parser::new(pairs).parse()
+}
+
+fn compile(src: &str, output_path: &str) -> std::result::Result<(), Vec<Error>> {
+    let ast = parse(src).map_err(|err| vec![err])?;
     // dbg!(&ast);
+    let errors = checker::check(&ast);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let ast = optimizer::optimize(ast);
     let path = Path::new(output_path);
     Codegen::compile(ast, path);
     Ok(())
@@ -47,9 +58,11 @@ fn main() {
     });
 
     let src = fs::read_to_string(&opts.input).unwrap();
-    if let Err(err) = compile(&src, &output_path) {
-        println!("--> {}:{}:{}", opts.input, err.line, err.col);
-        println!("{}", src.lines().nth(err.line - 1).unwrap());
-        println!("{}^ {}", " ".repeat(err.col - 1), err);
+    if let Err(errors) = compile(&src, &output_path) {
+        for err in errors {
+            println!("--> {}:{}:{}", opts.input, err.line, err.col);
+            println!("{}", src.lines().nth(err.line - 1).unwrap());
+            println!("{}^ {}", " ".repeat(err.col - 1), err);
+        }
     }
 }
@@ -0,0 +1,329 @@
+use crate::ast::*;
+use std::collections::HashSet;
+
+/// Simplifies a checked program before codegen by folding constant expressions, dropping code
+/// in a block that can never run and removing internal functions that are never used.
+pub fn optimize(mut items: Vec<Item>) -> Vec<Item> {
+    for item in &mut items {
+        if let Item::FunctionDef(def) = item {
+            visit(&mut def.code, &mut |expr| {
+                fold(expr);
+                remove_dead_code(expr);
+            });
+        }
+    }
+    remove_unused_functions(items)
+}
+
+/// Calls `f` on every expression in the tree, children first.
+fn visit(expr: &mut Expr, f: &mut impl FnMut(&mut Expr)) {
+    match &mut expr.kind {
+        ExprKind::Binary(expr) => {
+            visit(&mut expr.a, f);
+            visit(&mut expr.b, f);
+        }
+        ExprKind::Unary(expr) => visit(&mut expr.a, f),
+        ExprKind::Invoke(expr) => {
+            for param in &mut expr.params {
+                visit(param, f);
+            }
+        }
+        ExprKind::Block(expr) => {
+            for expr in &mut expr.exprs {
+                visit(expr, f);
+            }
+        }
+        ExprKind::Assignment(expr) => visit(&mut expr.val, f),
+        ExprKind::Return(expr) => {
+            if let Some(val) = &mut expr.val {
+                visit(val, f);
+            }
+        }
+        ExprKind::If(expr) => {
+            visit(&mut expr.cond, f);
+            visit(&mut expr.then_branch, f);
+            visit(&mut expr.else_branch, f);
+        }
+        ExprKind::While(expr) => {
+            visit(&mut expr.cond, f);
+            visit(&mut expr.body, f);
+        }
+        ExprKind::Store(expr) => {
+            visit(&mut expr.addr, f);
+            visit(&mut expr.val, f);
+        }
+        ExprKind::Cast(expr) => visit(&mut expr.val, f),
+        ExprKind::GlobalSet(expr) => visit(&mut expr.val, f),
+        ExprKind::Field(expr) => {
+            visit(&mut expr.addr, f);
+            if let Access::Store(val) = &mut expr.access {
+                visit(val, f);
+            }
+        }
+        ExprKind::Index(expr) => {
+            visit(&mut expr.addr, f);
+            visit(&mut expr.index, f);
+            if let Access::Store(val) = &mut expr.access {
+                visit(val, f);
+            }
+        }
+        ExprKind::IndirectCall(expr) => {
+            visit(&mut expr.callee, f);
+            for param in &mut expr.params {
+                visit(param, f);
+            }
+        }
+        ExprKind::Switch(expr) => {
+            visit(&mut expr.scrutinee, f);
+            for case in &mut expr.cases {
+                visit(&mut case.body, f);
+            }
+            visit(&mut expr.default, f);
+        }
+        ExprKind::Local(_)
+        | ExprKind::Constant(_)
+        | ExprKind::FloatConstant(_)
+        | ExprKind::StringLit(_)
+        | ExprKind::Break
+        | ExprKind::Continue
+        | ExprKind::AddrOf(_)
+        | ExprKind::GlobalGet(_)
+        | ExprKind::GlobalAddr(_)
+        | ExprKind::FuncAddr(_) => {}
+    }
+    f(expr);
+}
+
+/// Sign-extends the low `width` bits of `val`, which is how constants of narrower types are kept.
+fn wrap(val: i64, width: u32) -> i64 {
+    let shift = 64 - width;
+    (val << shift) >> shift
+}
+
+fn unsigned(val: i64, width: u32) -> u64 {
+    (val as u64) & (u64::MAX >> (64 - width))
+}
+
+/// Evaluates an integer operator the way LLVM would, giving up on operations that would be
+/// undefined at runtime such as division by zero or shifting by the width or more.
+fn fold_binary(
+    op: &BinaryOp,
+    a: &ConstantExpr,
+    b: &ConstantExpr,
+    cmp_ty: &Option<Type>,
+) -> Option<ConstantExpr> {
+    let width = a.ty.int_width()?;
+    let (x, y) = (wrap(a.val, width), wrap(b.val, width));
+    let (ux, uy) = (unsigned(a.val, width), unsigned(b.val, width));
+    let min = i64::MIN >> (64 - width);
+    let val = match op {
+        BinaryOp::Add => x.wrapping_add(y),
+        BinaryOp::Sub => x.wrapping_sub(y),
+        BinaryOp::Mult => x.wrapping_mul(y),
+        BinaryOp::Div | BinaryOp::SRem if y == 0 || (y == -1 && x == min) => return None,
+        BinaryOp::Div => x / y,
+        BinaryOp::SRem => x % y,
+        BinaryOp::UDiv | BinaryOp::URem if uy == 0 => return None,
+        BinaryOp::UDiv => (ux / uy) as i64,
+        BinaryOp::URem => (ux % uy) as i64,
+        BinaryOp::Lsh | BinaryOp::Rsh | BinaryOp::URsh if uy >= width as u64 => return None,
+        BinaryOp::Lsh => x << uy,
+        BinaryOp::Rsh => x >> uy,
+        BinaryOp::URsh => (ux >> uy) as i64,
+        BinaryOp::And => x & y,
+        BinaryOp::Or => x | y,
+        BinaryOp::Xor => x ^ y,
+        BinaryOp::Eq => (x == y) as i64,
+        BinaryOp::Ne => (x != y) as i64,
+        BinaryOp::SLt => (x < y) as i64,
+        BinaryOp::SLe => (x <= y) as i64,
+        BinaryOp::SGt => (x > y) as i64,
+        BinaryOp::SGe => (x >= y) as i64,
+        BinaryOp::ULt => (ux < uy) as i64,
+        BinaryOp::ULe => (ux <= uy) as i64,
+        BinaryOp::UGt => (ux > uy) as i64,
+        BinaryOp::UGe => (ux >= uy) as i64,
+    };
+    // Comparisons produce the type they name rather than the type of their operands
+    let ty = match cmp_ty {
+        Some(ty) => ty.clone(),
+        None => a.ty.clone(),
+    };
+    let val = wrap(val, ty.int_width()?);
+    Some(ConstantExpr { ty, val })
+}
+
+/// Replaces integer operators whose operands are constants with their result.
+fn fold(expr: &mut Expr) {
+    let folded = match &expr.kind {
+        ExprKind::Binary(bin) => match (&bin.a.kind, &bin.b.kind) {
+            (ExprKind::Constant(a), ExprKind::Constant(b)) => fold_binary(&bin.op, a, b, &bin.ty),
+            _ => None,
+        },
+        ExprKind::Unary(UnaryExpr {
+            op: UnaryOp::Not,
+            a,
+        }) => match &a.kind {
+            ExprKind::Constant(a) => Some(ConstantExpr {
+                ty: a.ty.clone(),
+                val: wrap(!a.val, a.ty.int_width().unwrap()),
+            }),
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(constant) = folded {
+        expr.kind = ExprKind::Constant(constant);
+    }
+}
+
+/// Drops the expressions in a block that follow one that diverges, which the checker and codegen
+/// already treat as never running.
+fn remove_dead_code(expr: &mut Expr) {
+    if let ExprKind::Block(block) = &mut expr.kind {
+        if let Some(i) = block.exprs.iter().position(Expr::diverges) {
+            block.exprs.truncate(i + 1);
+        }
+    }
+}
+
+/// Removes internal functions that can't be reached from any external function. Declarations that
+/// only give the signature of an indirect call are kept without their definitions.
+fn remove_unused_functions(mut items: Vec<Item>) -> Vec<Item> {
+    let mut internal = HashSet::new();
+    let mut unvisited = Vec::new();
+    for item in &items {
+        if let Item::FunctionDecl(decl) = item {
+            match decl.linkage {
+                Linkage::Internal => {
+                    internal.insert(decl.name.clone());
+                }
+                Linkage::External => unvisited.push(decl.name.clone()),
+            }
+        }
+    }
+
+    let mut used = HashSet::new();
+    let mut signatures = HashSet::new();
+    while let Some(name) = unvisited.pop() {
+        if !used.insert(name.clone()) {
+            continue;
+        }
+        for item in &mut items {
+            match item {
+                Item::FunctionDef(def) if def.name == name => {
+                    visit(&mut def.code, &mut |expr| match &expr.kind {
+                        ExprKind::Invoke(expr) => unvisited.push(expr.func_name.clone()),
+                        ExprKind::FuncAddr(expr) => unvisited.push(expr.func_name.clone()),
+                        // Only the declaration is needed for the type of the call, since calling the
+                        // function itself would take its address
+                        ExprKind::IndirectCall(expr) => {
+                            signatures.insert(expr.sig_name.clone());
+                        }
+                        _ => {}
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    items.retain_mut(|item| match item {
+        Item::FunctionDecl(decl) if internal.contains(&decl.name) && !used.contains(&decl.name) => {
+            // LLVM doesn't allow internal functions without a body, but an external declaration
+            // that is never referenced doesn't end up in the object file
+            decl.linkage = Linkage::External;
+            signatures.contains(&decl.name)
+        }
+        Item::FunctionDecl(_) => true,
+        Item::FunctionDef(FunctionDefItem { name, .. }) => {
+            !internal.contains(name) || used.contains(name)
+        }
+        Item::Global(_) | Item::Struct(_) => true,
+    });
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant(ty: Type, val: i64) -> ConstantExpr {
+        ConstantExpr { ty, val }
+    }
+
+    fn fold_int(op: &BinaryOp, ty: Type, a: i64, b: i64) -> Option<i64> {
+        fold_binary(op, &constant(ty.clone(), a), &constant(ty, b), &None).map(|c| c.val)
+    }
+
+    #[test]
+    fn division_by_zero_is_not_folded() {
+        for op in [
+            BinaryOp::Div,
+            BinaryOp::SRem,
+            BinaryOp::UDiv,
+            BinaryOp::URem,
+        ] {
+            assert_eq!(fold_int(&op, Type::I32, 7, 0), None);
+        }
+    }
+
+    #[test]
+    fn min_by_minus_one_is_not_folded() {
+        assert_eq!(fold_int(&BinaryOp::Div, Type::I64, i64::MIN, -1), None);
+        assert_eq!(fold_int(&BinaryOp::SRem, Type::I64, i64::MIN, -1), None);
+        assert_eq!(fold_int(&BinaryOp::Div, Type::I8, -128, -1), None);
+        assert_eq!(fold_int(&BinaryOp::SRem, Type::I16, -32768, -1), None);
+        // Unsigned division has no overflow
+        assert_eq!(fold_int(&BinaryOp::UDiv, Type::I8, -128, -1), Some(0));
+    }
+
+    #[test]
+    fn oversized_shifts_are_not_folded() {
+        for op in [BinaryOp::Lsh, BinaryOp::Rsh, BinaryOp::URsh] {
+            assert_eq!(fold_int(&op, Type::I8, 1, 8), None);
+            assert_eq!(fold_int(&op, Type::I32, 1, 32), None);
+            assert_eq!(fold_int(&op, Type::I64, 1, 64), None);
+            // Negative amounts are huge unsigned ones
+            assert_eq!(fold_int(&op, Type::I64, 1, -1), None);
+        }
+        assert_eq!(fold_int(&BinaryOp::Lsh, Type::I8, 1, 7), Some(-128));
+        assert_eq!(fold_int(&BinaryOp::URsh, Type::I8, -128, 7), Some(1));
+        assert_eq!(fold_int(&BinaryOp::Rsh, Type::I8, -128, 7), Some(-1));
+    }
+
+    #[test]
+    fn narrow_types_wrap() {
+        assert_eq!(fold_int(&BinaryOp::Add, Type::I8, 127, 1), Some(-128));
+        assert_eq!(fold_int(&BinaryOp::Sub, Type::I8, -128, 1), Some(127));
+        assert_eq!(fold_int(&BinaryOp::Mult, Type::I8, 3, 64), Some(-64));
+        assert_eq!(fold_int(&BinaryOp::Add, Type::I16, 32767, 1), Some(-32768));
+        assert_eq!(fold_int(&BinaryOp::Mult, Type::I16, 256, 256), Some(0));
+        // Constants of unsigned values are kept sign-extended
+        assert_eq!(fold_int(&BinaryOp::Add, Type::I8, 255, 0), Some(-1));
+    }
+
+    #[test]
+    fn comparisons_have_their_own_type() {
+        let cmp = |op, ty: Type, a, b, cmp_ty: Type| {
+            fold_binary(
+                &op,
+                &constant(ty.clone(), a),
+                &constant(ty, b),
+                &Some(cmp_ty),
+            )
+            .unwrap()
+        };
+        let result = cmp(BinaryOp::SLt, Type::I8, -1, 1, Type::I64);
+        assert_eq!(result.ty, Type::I64);
+        assert_eq!(result.val, 1);
+        // -1 is the largest unsigned i8
+        let result = cmp(BinaryOp::ULt, Type::I8, -1, 1, Type::I32);
+        assert_eq!(result.ty, Type::I32);
+        assert_eq!(result.val, 0);
+        // A true i8 result is zero-extended, so it stays 1 rather than becoming -1
+        let result = cmp(BinaryOp::Eq, Type::I64, 5, 5, Type::I8);
+        assert_eq!(result.ty, Type::I8);
+        assert_eq!(result.val, 1);
+    }
+}
@@ -6,6 +6,7 @@ pub struct Parser {
     i: usize,
     tokens: Vec<TokenPair>,
     items: Vec<Item>,
+    loop_depth: usize,
 }
 
 impl Parser {
@@ -14,6 +15,7 @@ impl Parser {
             i: 0,
             tokens,
             items: Vec::new(),
+            loop_depth: 0,
         }
     }
 
@@ -39,14 +41,60 @@ impl Parser {
         self.tokens[self.i - 1].error()
     }
 
+    fn decls(&self) -> impl Iterator<Item = &FunctionDeclItem> {
+        self.items.iter().filter_map(|item| match item {
+            Item::FunctionDecl(decl) => Some(decl),
+            _ => None,
+        })
+    }
+
     fn find_decl(&self, name: &str) -> Result<&FunctionDeclItem> {
+        self.decls()
+            .find(|decl| decl.name == name)
+            .ok_or_else(|| Error {
+                msg: Some(format!("call to undeclared function `{}`", name)),
+                ..self.error()
+            })
+    }
+
+    /// Fails if a function named `name` has already been declared, since a later declaration
+    /// would silently be ignored.
+    fn check_redeclaration(&self, name: &str, line: usize, col: usize) -> Result<()> {
+        if self.decls().any(|decl| decl.name == name) {
+            return Err(Error {
+                line,
+                col,
+                msg: Some(format!("function `{}` is declared more than once", name)),
+            });
+        }
+        Ok(())
+    }
+
+    /// Fails if a function named `name` already has a body, since codegen would append the second
+    /// body to the same function where it could never run.
+    fn check_redefinition(&self, name: &str, line: usize, col: usize) -> Result<()> {
+        let defined = self.items.iter().any(|item| match item {
+            Item::FunctionDef(def) => def.name == name,
+            _ => false,
+        });
+        if defined {
+            return Err(Error {
+                line,
+                col,
+                msg: Some(format!("function `{}` is already defined", name)),
+            });
+        }
+        Ok(())
+    }
+
+    fn find_global(&self, index: u32) -> Result<&GlobalItem> {
         self.items
             .iter()
             .filter_map(|item| match item {
-                Item::FunctionDecl(decl) => Some(decl),
+                Item::Global(global) => Some(global),
                 _ => None,
             })
-            .find(|decl| decl.name == name)
+            .nth(index as usize)
             .ok_or_else(|| self.error())
     }
 
@@ -72,94 +120,401 @@ impl Parser {
         Ok(str)
     }
 
-    fn parse_type_arr(&mut self, length: u32) -> Result<Vec<Type>> {
-        let mut types = Vec::new();
-        for _ in 0..length {
+    fn find_struct(&self, index: u32) -> Result<&StructItem> {
+        self.items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Struct(def) => Some(def),
+                _ => None,
+            })
+            .nth(index as usize)
+            .ok_or_else(|| self.error())
+    }
+
+    /// Reads 64 bits as hex digits, one per token slot, most significant first.
+    fn parse_wide_digits(&mut self, num_pairs: u32) -> Result<u64> {
+        let mut bits: u64 = 0;
+        for _ in 0..num_pairs {
             let tok = self.next()?;
-            let type_id = tok.data[6];
-            match Type::from_id(type_id) {
-                Some(ty) => types.push(ty),
+            for digit in tok.data {
+                if digit > 0xF {
+                    return Err(self.error());
+                }
+                bits = match bits.checked_mul(16) {
+                    Some(bits) => bits | digit as u64,
+                    None => return Err(self.error()),
+                };
+            }
+        }
+        Ok(bits)
+    }
+
+    fn parse_type(&mut self) -> Result<Type> {
+        let tok = self.next()?;
+        let type_id = tok.data[6];
+        let len = tok.data[7];
+        let ty = match tok.data[5] {
+            0 => match Type::from_id(type_id) {
+                Some(ty) => ty,
                 None => return Err(self.error()),
+            },
+            // Struct types are referenced by the index of their definition
+            1 => {
+                self.find_struct(type_id)?;
+                Type::Struct(type_id)
             }
+            _ => return Err(self.error()),
+        };
+        // A non-zero length makes this an array of the type
+        match (ty, len) {
+            (ty, 0) => Ok(ty),
+            (Type::Unit, _) => Err(self.error()),
+            (ty, len) => Ok(Type::Array(Box::new(ty), len)),
         }
-        Ok(types)
+    }
+
+    fn parse_type_arr(&mut self, length: u32) -> Result<Vec<Type>> {
+        (0..length)
+            .map(|_| match self.parse_type()? {
+                // Parameters and locals can't be unit
+                Type::Unit => Err(self.error()),
+                ty => Ok(ty),
+            })
+            .collect()
+    }
+
+    fn parse_exprs_until_end(&mut self) -> Result<Vec<Expr>> {
+        let mut exprs = Vec::new();
+
+        loop {
+            // peek next to see if it ends the list
+            let tok = self.next()?;
+            if let [0, 0, 0, 0, 1, 0, 0, 0] = tok.data {
+                break;
+            }
+            self.i -= 1;
+
+            exprs.push(self.parse_expr()?);
+        }
+
+        Ok(exprs)
+    }
+
+    /// Parses the arguments to a call with the signature of the declaration `name`. An `argc` of 0
+    /// reads one argument per parameter, so `name` must be declared earlier in the file for the
+    /// arguments to be counted, and calling a function that isn't is an error. Otherwise exactly
+    /// `argc - 1` arguments are read and left for the checker to match against the declaration, so
+    /// the function can be declared anywhere.
+    fn parse_args(&mut self, name: &str, argc: u32) -> Result<Vec<Expr>> {
+        if argc > 0 {
+            return (0..argc - 1).map(|_| self.parse_expr()).collect();
+        }
+
+        let decl = self.find_decl(name)?;
+        let params_len = decl.params.len();
+        let variadic = decl.variadic;
+        let params: Result<Vec<Expr>> = (0..params_len).map(|_| self.parse_expr()).collect();
+        let mut params = params?;
+        if variadic {
+            params.append(&mut self.parse_exprs_until_end()?);
+        }
+        Ok(params)
     }
 
     fn parse_expr(&mut self) -> Result<Expr> {
-        let tok = self.next()?;
-        Ok(match tok.data {
+        let start = self.i;
+        let kind = self.parse_expr_kind()?;
+        let tok = &self.tokens[start];
+        Ok(Expr {
+            kind,
+            line: tok.line,
+            col: tok.col,
+        })
+    }
+
+    fn parse_expr_kind(&mut self) -> Result<ExprKind> {
+        let data = self.next()?.data;
+        Ok(match data {
             // Binary
-            [0, op_id, 0, 0, 0, 0, 0, 0] => {
+            [0, op_id, 0, 0, 0, type_id, 0, 0] => {
                 let op = match BinaryOp::from_id(op_id) {
                     Some(op) => op,
                     None => return Err(self.error()),
                 };
+                let ty = if op.is_comparison() {
+                    match Type::from_id(type_id) {
+                        Some(Type::Unit | Type::F32 | Type::F64) | None => return Err(self.error()),
+                        Some(ty) => Some(ty),
+                    }
+                } else if type_id == 0 {
+                    None
+                } else {
+                    return Err(self.error());
+                };
                 let a = Box::new(self.parse_expr()?);
                 let b = Box::new(self.parse_expr()?);
-                Expr::Binary(BinaryExpr { op, a, b })
+                ExprKind::Binary(BinaryExpr { op, a, b, ty })
             }
             // Unary
-            [0, op_id, 0, 0, 0, 0, 1, 0] => {
-                let op = match UnaryOp::from_id(op_id) {
+            [0, op_id, width_id, 0, 0, ext_id, 1, 0] => {
+                // The type and extension of a deref are ids offset by 1, with 0 leaving them out
+                let ty = match width_id {
+                    0 => None,
+                    _ => match Type::from_id(width_id - 1) {
+                        Some(Type::Unit) | None => return Err(self.error()),
+                        ty => ty,
+                    },
+                };
+                let ext = match ext_id {
+                    0 => None,
+                    _ => match Extension::from_id(ext_id - 1) {
+                        Some(ext) => Some(ext),
+                        None => return Err(self.error()),
+                    },
+                };
+                if ext.is_some() && ty.as_ref().is_some_and(Type::is_float) {
+                    return Err(self.error());
+                }
+                let op = match UnaryOp::from_id(op_id, ty, ext) {
                     Some(op) => op,
                     None => return Err(self.error()),
                 };
                 let a = Box::new(self.parse_expr()?);
-                Expr::Unary(UnaryExpr { op, a })
+                ExprKind::Unary(UnaryExpr { op, a })
             }
             // Invoke
-            [0, 0, 0, 0, 0, 0, 2, 0] => {
+            [0, 0, argc, 0, 0, 0, 2, 0] => {
                 let name = self.parse_string()?;
-                let decl = self.find_decl(&name)?;
-                let params_len = decl.params.len();
-                let params: Result<Vec<Expr>> =
-                    (0..params_len).map(|_| self.parse_expr()).collect();
-                Expr::Invoke(InvokeExpr {
+                let params = self.parse_args(&name, argc)?;
+                ExprKind::Invoke(InvokeExpr {
                     func_name: name,
-                    params: params?,
+                    params,
                 })
             }
             // Block
             [0, 0, 0, 0, 0, 0, 3, 0] => {
-                let mut exprs = Vec::new();
-
-                loop {
-                    // peek next to see if it ends the block
-                    let tok = self.next()?;
-                    if let [0, 0, 0, 0, 1, 0, 0, 0] = tok.data {
-                        break;
-                    }
-                    self.i -= 1;
-
-                    exprs.push(self.parse_expr()?);
-                }
-
-                Expr::Block(BlockExpr { exprs })
+                let exprs = self.parse_exprs_until_end()?;
+                ExprKind::Block(BlockExpr { exprs })
             }
             // Assignment
             [0, 0, local, 0, 0, 0, 4, 0] => {
                 let val = Box::new(self.parse_expr()?);
-                Expr::Assignment(AssignmentExpr { local, val })
+                ExprKind::Assignment(AssignmentExpr { local, val })
             }
             // Local
-            [0, 0, local, 0, 0, 0, 5, 0] => Expr::Local(LocalExpr { local }),
+            [0, 0, local, 0, 0, 0, 5, 0] => ExprKind::Local(LocalExpr { local }),
             // Constant
             [0, 0, val, 0, 0, type_id, 6, 0] => {
                 let ty = match Type::from_id(type_id) {
+                    Some(Type::Unit) | None => return Err(self.error()),
                     Some(ty) => ty,
-                    None => return Err(self.error()),
                 };
-                Expr::Constant(ConstantExpr { ty, val })
+                if ty.is_float() {
+                    let val = f64::from(val);
+                    ExprKind::FloatConstant(FloatConstantExpr { ty, val })
+                } else {
+                    let width = ty.int_width().unwrap();
+                    if u64::from(val) > int_limit(width, false) {
+                        return Err(self.error());
+                    }
+                    let val = i64::from(val);
+                    ExprKind::Constant(ConstantExpr { ty, val })
+                }
+            }
+            // Wide constant
+            [0, 0, num_pairs, 0, 0, type_id, 18, negative] => {
+                let ty = match Type::from_id(type_id) {
+                    Some(Type::Unit) | None => return Err(self.error()),
+                    Some(ty) => ty,
+                };
+                let negative = match negative {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(self.error()),
+                };
+                let bits = self.parse_wide_digits(num_pairs)?;
+                if ty.is_float() {
+                    // Wide float constants hold the bits of an f64
+                    let val = f64::from_bits(bits);
+                    let val = if negative { -val } else { val };
+                    ExprKind::FloatConstant(FloatConstantExpr { ty, val })
+                } else {
+                    let width = ty.int_width().unwrap();
+                    if bits > int_limit(width, negative) {
+                        return Err(self.error());
+                    }
+                    let val = if negative {
+                        (bits as i64).wrapping_neg()
+                    } else {
+                        bits as i64
+                    };
+                    ExprKind::Constant(ConstantExpr { ty, val })
+                }
             }
             // Return
             [0, 0, 0, 0, 0, 0, 7, 0] => {
                 let val = Box::new(self.parse_expr()?);
-                Expr::Return(ReturnExpr { val })
+                ExprKind::Return(ReturnExpr { val: Some(val) })
             }
+            // Return from a unit function
+            [0, 0, 1, 0, 0, 0, 7, 0] => ExprKind::Return(ReturnExpr { val: None }),
             // String literal
             [0, 0, 0, 0, 0, 0, 8, 0] => {
                 let str = self.parse_string()?;
-                Expr::StringLit(StringLitExpr { str })
+                ExprKind::StringLit(StringLitExpr { str })
+            }
+            // If
+            [0, 0, 0, 0, 0, 0, 9, 0] => {
+                let cond = Box::new(self.parse_expr()?);
+                let then_branch = Box::new(self.parse_expr()?);
+                let else_branch = Box::new(self.parse_expr()?);
+                ExprKind::If(IfExpr {
+                    cond,
+                    then_branch,
+                    else_branch,
+                })
+            }
+            // While
+            [0, 0, 0, 0, 0, 0, 10, 0] => {
+                let cond = Box::new(self.parse_expr()?);
+                self.loop_depth += 1;
+                let body = self.parse_expr();
+                self.loop_depth -= 1;
+                let body = Box::new(body?);
+                ExprKind::While(WhileExpr { cond, body })
+            }
+            // Break
+            [0, 0, 0, 0, 0, 0, 11, 0] if self.loop_depth > 0 => ExprKind::Break,
+            // Continue
+            [0, 0, 0, 0, 0, 0, 12, 0] if self.loop_depth > 0 => ExprKind::Continue,
+            // Store
+            [0, 0, 0, 0, 0, type_id, 13, 0] => {
+                let ty = match Type::from_id(type_id) {
+                    Some(Type::Unit) | None => return Err(self.error()),
+                    Some(ty) => ty,
+                };
+                let addr = Box::new(self.parse_expr()?);
+                let val = Box::new(self.parse_expr()?);
+                ExprKind::Store(StoreExpr { ty, addr, val })
+            }
+            // Address of
+            [0, 0, local, 0, 0, 0, 14, 0] => ExprKind::AddrOf(AddrOfExpr { local }),
+            // Address of global
+            [0, 0, global, 0, 0, 1, 14, 0] => {
+                self.find_global(global)?;
+                ExprKind::GlobalAddr(GlobalAddrExpr { global })
+            }
+            // Cast
+            [0, op_id, 0, 0, 0, type_id, 15, 0] => {
+                let op = match CastOp::from_id(op_id) {
+                    Some(op) => op,
+                    None => return Err(self.error()),
+                };
+                let ty = match Type::from_id(type_id) {
+                    Some(Type::Unit) | None => return Err(self.error()),
+                    Some(ty) => ty,
+                };
+                let val = Box::new(self.parse_expr()?);
+                ExprKind::Cast(CastExpr { op, ty, val })
+            }
+            // Field access
+            [0, struct_id, field, 0, 0, mode, 19, 0] => {
+                let field_ty = match self.find_struct(struct_id)?.fields.get(field as usize) {
+                    Some(ty) => ty,
+                    None => return Err(self.error()),
+                };
+                // Aggregate fields can only have their address taken
+                let aggregate = field_ty.is_aggregate();
+                let addr = Box::new(self.parse_expr()?);
+                let access = match mode {
+                    0 if !aggregate => Access::Load,
+                    1 if !aggregate => Access::Store(Box::new(self.parse_expr()?)),
+                    2 => Access::Address,
+                    _ => return Err(self.error()),
+                };
+                ExprKind::Field(FieldExpr {
+                    struct_id,
+                    field,
+                    addr,
+                    access,
+                })
+            }
+            // Function address
+            [0, 0, 0, 0, 0, 0, 21, 0] => {
+                let func_name = self.parse_string()?;
+                ExprKind::FuncAddr(FuncAddrExpr { func_name })
+            }
+            // Indirect call
+            [0, 0, argc, 0, 0, 0, 22, 0] => {
+                let sig_name = self.parse_string()?;
+                let callee = Box::new(self.parse_expr()?);
+                let params = self.parse_args(&sig_name, argc)?;
+                ExprKind::IndirectCall(IndirectCallExpr {
+                    sig_name,
+                    callee,
+                    params,
+                })
+            }
+            // Switch
+            [0, 0, num_cases, 0, 0, 0, 23, 0] => {
+                let scrutinee = Box::new(self.parse_expr()?);
+                let mut cases: Vec<SwitchCase> = Vec::new();
+                for _ in 0..num_cases {
+                    let Expr { kind, line, col } = self.parse_expr()?;
+                    let val = match kind {
+                        ExprKind::Constant(val) => val,
+                        _ => return Err(self.error()),
+                    };
+                    let body = self.parse_expr()?;
+                    cases.push(SwitchCase {
+                        val,
+                        body,
+                        line,
+                        col,
+                    });
+                }
+                let default = Box::new(self.parse_expr()?);
+                ExprKind::Switch(SwitchExpr {
+                    scrutinee,
+                    cases,
+                    default,
+                })
+            }
+            // Index
+            [0, 0, 0, 0, 0, mode, 20, 0] => {
+                let elem_ty = match self.parse_type()? {
+                    Type::Unit => return Err(self.error()),
+                    ty => ty,
+                };
+                // Aggregate elements can only have their address taken
+                let aggregate = elem_ty.is_aggregate();
+                let addr = Box::new(self.parse_expr()?);
+                let index = Box::new(self.parse_expr()?);
+                let access = match mode {
+                    0 if !aggregate => Access::Load,
+                    1 if !aggregate => Access::Store(Box::new(self.parse_expr()?)),
+                    2 => Access::Address,
+                    _ => return Err(self.error()),
+                };
+                ExprKind::Index(IndexExpr {
+                    elem_ty,
+                    addr,
+                    index,
+                    access,
+                })
+            }
+            // Global get
+            [0, 0, global, 0, 0, 0, 16, 0] => {
+                self.find_global(global)?;
+                ExprKind::GlobalGet(GlobalGetExpr { global })
+            }
+            // Global set
+            [0, 0, global, 0, 0, 0, 17, 0] => {
+                if !self.find_global(global)?.mutable {
+                    return Err(self.error());
+                }
+                let val = Box::new(self.parse_expr()?);
+                ExprKind::GlobalSet(GlobalSetExpr { global, val })
             }
             _ => return Err(self.error()),
         })
@@ -167,48 +522,157 @@ impl Parser {
 
     fn parse_func_decl(
         &mut self,
+        variadic: u32,
         linkage: u32,
         return_ty: u32,
         num_params: u32,
     ) -> Result<FunctionDeclItem> {
+        let tok = &self.tokens[self.i - 1];
+        let (line, col) = (tok.line, tok.col);
         let name = self.parse_string()?;
-        let linkage = match linkage {
-            0 => Linkage::External,
-            1 => Linkage::Internal,
-            _ => return Err(self.error()),
-        };
+        self.check_redeclaration(&name, line, col)?;
         let return_ty = match Type::from_id(return_ty) {
             Some(ty) => ty,
             None => return Err(self.error()),
         };
+        self.parse_signature(name, variadic, linkage, return_ty, num_params)
+    }
+
+    fn parse_signature(
+        &mut self,
+        name: String,
+        variadic: u32,
+        linkage: u32,
+        return_ty: Type,
+        num_params: u32,
+    ) -> Result<FunctionDeclItem> {
+        let variadic = match variadic {
+            0 => false,
+            1 => true,
+            _ => return Err(self.error()),
+        };
+        let linkage = match Linkage::from_id(linkage) {
+            Some(linkage) => linkage,
+            None => return Err(self.error()),
+        };
         let params = self.parse_type_arr(num_params)?;
+        if params.iter().any(Type::is_aggregate) {
+            return Err(self.error());
+        }
         Ok(FunctionDeclItem {
             name,
             return_ty,
             params,
             linkage,
+            variadic,
         })
     }
 
-    fn parse_func_def(&mut self, num_locals: u32) -> Result<FunctionDefItem> {
+    /// Parses a function definition, which declares itself if `sig` holds the linkage and number
+    /// of parameters of its signature.
+    fn parse_func_def(
+        &mut self,
+        num_locals: u32,
+        sig: Option<(u32, u32)>,
+    ) -> Result<FunctionDefItem> {
+        let tok = &self.tokens[self.i - 1];
+        let (line, col) = (tok.line, tok.col);
         let name = self.parse_string()?;
+        self.check_redefinition(&name, line, col)?;
+        if let Some((linkage, num_params)) = sig {
+            self.check_redeclaration(&name, line, col)?;
+            let return_ty = match self.parse_type()? {
+                ty if ty.is_aggregate() => return Err(self.error()),
+                ty => ty,
+            };
+            let decl = self.parse_signature(name.clone(), 0, linkage, return_ty, num_params)?;
+            // Declared before the body is parsed so the function can call itself
+            self.items.push(Item::FunctionDecl(decl));
+        }
         let locals = self.parse_type_arr(num_locals)?;
         let code = self.parse_expr()?;
-        Ok(FunctionDefItem { name, locals, code })
+        Ok(FunctionDefItem {
+            name,
+            locals,
+            code,
+            line,
+            col,
+        })
+    }
+
+    fn parse_global(&mut self, mutable: u32, linkage: u32, has_init: u32) -> Result<GlobalItem> {
+        let name = self.parse_string()?;
+        let linkage = match Linkage::from_id(linkage) {
+            Some(linkage) => linkage,
+            None => return Err(self.error()),
+        };
+        let mutable = match mutable {
+            0 => false,
+            1 => true,
+            _ => return Err(self.error()),
+        };
+        let ty = match self.parse_type()? {
+            Type::Unit => return Err(self.error()),
+            ty => ty,
+        };
+        let init = match has_init {
+            0 => None,
+            1 if !ty.is_aggregate() => match self.parse_expr()? {
+                init @ Expr {
+                    kind: ExprKind::Constant(_) | ExprKind::FloatConstant(_),
+                    ..
+                } => Some(init),
+                _ => return Err(self.error()),
+            },
+            _ => return Err(self.error()),
+        };
+        Ok(GlobalItem {
+            name,
+            ty,
+            linkage,
+            mutable,
+            init,
+        })
+    }
+
+    fn parse_struct(&mut self, num_fields: u32) -> Result<StructItem> {
+        let name = self.parse_string()?;
+        let fields = self.parse_type_arr(num_fields)?;
+        Ok(StructItem { name, fields })
     }
 
     fn parse_item(&mut self) -> Result<Item> {
         let tok = self.next()?;
         Ok(match tok.data {
             // Function declaration
-            [0, 0, 0, 0, 0, linkage, return_ty, num_params] => {
-                Item::FunctionDecl(self.parse_func_decl(linkage, return_ty, num_params)?)
+            [0, 0, 0, variadic, 0, linkage, return_ty, num_params] => {
+                Item::FunctionDecl(self.parse_func_decl(variadic, linkage, return_ty, num_params)?)
             }
             // Function definition
             [0, 0, 1, 0, 0, 0, num_locals, 0] => {
-                Item::FunctionDef(self.parse_func_def(num_locals)?)
+                Item::FunctionDef(self.parse_func_def(num_locals, None)?)
+            }
+            // Function definition with its own declaration
+            [0, 0, 1, 1, 0, linkage, num_locals, num_params] => {
+                Item::FunctionDef(self.parse_func_def(num_locals, Some((linkage, num_params)))?)
             }
+            // Global variable
+            [0, 0, 2, mutable, 0, linkage, 0, has_init] => {
+                Item::Global(self.parse_global(mutable, linkage, has_init)?)
+            }
+            // Struct definition
+            [0, 0, 3, 0, 0, 0, num_fields, 0] => Item::Struct(self.parse_struct(num_fields)?),
             _ => return Err(self.error()),
         })
     }
 }
+
+/// The largest magnitude a constant of an integer type `width` bits wide can have. Narrower types
+/// accept their unsigned range for positive values, but i64 constants have to fit in an i64.
+fn int_limit(width: u32, negative: bool) -> u64 {
+    match (width, negative) {
+        (_, true) => 1 << (width - 1),
+        (64, false) => i64::MAX as u64,
+        (_, false) => (1 << width) - 1,
+    }
+}
@@ -18,6 +18,7 @@ impl Token {
         Error {
             line: self.line,
             col: self.col,
+            msg: None,
         }
     }
 }
@@ -64,6 +65,7 @@ impl TokenPair {
         Error {
             line: self.line,
             col: self.col,
+            msg: None,
         }
     }
 }